@@ -0,0 +1,250 @@
+use std::{cmp::Ordering, time::{SystemTime, UNIX_EPOCH}};
+
+use serde_json::json;
+
+use crate::encoding;
+
+#[derive(Debug, Clone)]
+pub struct Entry{
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey{
+    Name,
+    Size,
+    Modified,
+}
+
+/// reads `sort=name|size|mtime` and `order=asc|desc` from the query string
+pub fn sort_from_query(path: &str)->(SortKey,bool){
+    let mut key=SortKey::Name;
+    let mut desc=false;
+    let query=path.split_once('?').map_or("",|(_,q)|q);
+    let query=query.split('#').next().unwrap_or("");
+    for pair in query.split('&'){
+        match pair.split_once('=').unwrap_or((pair,"")){
+            ("sort","size")=>key=SortKey::Size,
+            ("sort","mtime")=>key=SortKey::Modified,
+            ("sort","name")=>key=SortKey::Name,
+            ("order","desc")=>desc=true,
+            ("order","asc")=>desc=false,
+            _=>(),
+        }
+    }
+    (key,desc)
+}
+
+/// directories always come first, the chosen key only orders within each group
+pub fn sort(entries: &mut [Entry], key: SortKey, desc: bool){
+    entries.sort_by(|a,b|{
+        let ord=match key{
+            SortKey::Name=>a.name.cmp(&b.name),
+            SortKey::Size=>a.size.cmp(&b.size).then_with(||a.name.cmp(&b.name)),
+            SortKey::Modified=>a.modified.cmp(&b.modified).then_with(||a.name.cmp(&b.name)),
+        };
+        let ord=if desc { ord.reverse() } else { ord };
+        match (a.is_dir,b.is_dir){
+            (true,false)=>Ordering::Less,
+            (false,true)=>Ordering::Greater,
+            _=>ord,
+        }
+    });
+}
+
+fn html_escape(s: &str)->String{
+    let mut out=String::with_capacity(s.len());
+    for c in s.chars(){
+        match c{
+            '&'=>out.push_str("&amp;"),
+            '<'=>out.push_str("&lt;"),
+            '>'=>out.push_str("&gt;"),
+            '"'=>out.push_str("&quot;"),
+            '\''=>out.push_str("&#39;"),
+            _=>out.push(c),
+        }
+    }
+    out
+}
+
+fn url_encode(s: &str)->String{
+    let mut out=String::with_capacity(s.len());
+    for b in s.bytes(){
+        match b{
+            b'A'..=b'Z'|b'a'..=b'z'|b'0'..=b'9'|b'-'|b'_'|b'.'|b'~'=>out.push(b as char),
+            _=>out.push_str(&format!("%{:02X}",b)),
+        }
+    }
+    out
+}
+
+fn human_size(size: u64)->String{
+    const UNITS: [&str; 5]=["B","KiB","MiB","GiB","TiB"];
+    let mut value=size as f64;
+    let mut unit=0;
+    while value>=1024.0 && unit<UNITS.len()-1{
+        value/=1024.0;
+        unit+=1;
+    }
+    if unit==0 { format!("{} {}",size,UNITS[0]) } else { format!("{:.1} {}",value,UNITS[unit]) }
+}
+
+fn unix_secs(t: Option<SystemTime>)->Option<u64>{
+    t.and_then(|t|t.duration_since(UNIX_EPOCH).ok()).map(|d|d.as_secs())
+}
+
+/// `base` is the url path of the directory and must end with a slash
+pub fn render_html(base: &str, entries: &[Entry], key: SortKey, desc: bool)->String{
+    let title=html_escape(base);
+    let toggle=|k: SortKey, q: &str|{
+        let order=if k==key && !desc { "desc" } else { "asc" };
+        format!("?sort={}&amp;order={}",q,order)
+    };
+
+    let mut html=String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!("<title>Index of {}</title>\n",title));
+    html.push_str("<style>body{font-family:monospace}td{padding:0 1em}td.size{text-align:right}</style>\n");
+    html.push_str("</head>\n<body>\n");
+    html.push_str(&format!("<h1>Index of {}</h1>\n<table>\n",title));
+    html.push_str(&format!(
+        "<tr><th><a href=\"{}\">Name</a></th><th><a href=\"{}\">Size</a></th><th><a href=\"{}\">Modified</a></th></tr>\n",
+        toggle(SortKey::Name,"name"),toggle(SortKey::Size,"size"),toggle(SortKey::Modified,"mtime"),
+    ));
+    if base!="/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for e in entries{
+        let slash=if e.is_dir { "/" } else { "" };
+        let size=if e.is_dir { "-".to_owned() } else { human_size(e.size) };
+        let modified=e.modified.map(httpdate::fmt_http_date).unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}{}{}\">{}{}</a></td><td class=\"size\">{}</td><td>{}</td></tr>\n",
+            html_escape(base),url_encode(&e.name),slash,html_escape(&e.name),slash,size,modified,
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+pub fn render_json(base: &str, entries: &[Entry])->String{
+    let items: Vec<_>=entries.iter().map(|e|json!({
+        "name": e.name,
+        "type": if e.is_dir { "directory" } else { "file" },
+        "size": e.size,
+        "modified": unix_secs(e.modified),
+    })).collect();
+    json!({ "path": base, "entries": items }).to_string()
+}
+
+/// json when the client names it with a q-value above 0 and doesnt weigh html higher
+pub fn wants_json(accept: Option<&Vec<String>>)->bool{
+    let Some(accept)=accept else { return false };
+    let types=encoding::parse(&accept.join(","));
+    let q=|name: &str|types.iter().find(|(t,_)|t==name).map_or(0.0,|(_,q)|*q);
+    q("application/json")>0.0 && q("application/json")>=q("text/html")
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::time::Duration;
+
+    fn entry(name: &str, is_dir: bool, size: u64, secs: u64)->Entry{
+        Entry{ name: name.to_owned(), is_dir, size, modified: Some(UNIX_EPOCH+Duration::from_secs(secs)) }
+    }
+
+    fn names(entries: &[Entry])->Vec<&str>{
+        entries.iter().map(|e|e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn sort_keys_from_the_query(){
+        let cases=[
+            ("/dir/",(SortKey::Name,false)),
+            ("/dir/?sort=size",(SortKey::Size,false)),
+            ("/dir/?sort=mtime&order=desc",(SortKey::Modified,true)),
+            ("/dir/?order=desc&sort=name#top",(SortKey::Name,true)),
+            ("/dir/?sort=bogus&order=sideways",(SortKey::Name,false)),
+        ];
+        for (path,expected) in cases{
+            assert_eq!(sort_from_query(path), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn directories_come_first(){
+        let all=vec![entry("b.txt",false,10,3),entry("z",true,0,1),entry("a.txt",false,30,2),entry("c.txt",false,10,1),entry("m",true,0,2)];
+        let cases=[
+            (SortKey::Name,false,["m","z","a.txt","b.txt","c.txt"]),
+            (SortKey::Name,true,["z","m","c.txt","b.txt","a.txt"]),
+            // equal sizes fall back to the name
+            (SortKey::Size,false,["m","z","b.txt","c.txt","a.txt"]),
+            (SortKey::Modified,false,["z","m","c.txt","a.txt","b.txt"]),
+            (SortKey::Modified,true,["m","z","b.txt","a.txt","c.txt"]),
+        ];
+        for (key,desc,expected) in cases{
+            let mut entries=all.clone();
+            sort(&mut entries, key, desc);
+            assert_eq!(names(&entries), expected, "{key:?} desc={desc}");
+        }
+    }
+
+    #[test]
+    fn escaping(){
+        assert_eq!(html_escape("<a href=\"x\">&'</a>"), "&lt;a href=&quot;x&quot;&gt;&amp;&#39;&lt;/a&gt;");
+        assert_eq!(html_escape("grüße"), "grüße");
+        assert_eq!(url_encode("a b&c<d>.txt"), "a%20b%26c%3Cd%3E.txt");
+        assert_eq!(url_encode("grüße"), "gr%C3%BC%C3%9Fe");
+        assert_eq!(url_encode("safe-_.~AZ09"), "safe-_.~AZ09");
+    }
+
+    #[test]
+    fn html_listing(){
+        let entries=[entry("sub dir",true,0,0),entry("<b>&.txt",false,2048,0)];
+        let html=render_html("/a&b/", &entries, SortKey::Size, false);
+        assert!(html.contains("<title>Index of /a&amp;b/</title>"));
+        assert!(html.contains("<a href=\"../\">../</a>"));
+        assert!(html.contains("<a href=\"/a&amp;b/sub%20dir/\">sub dir/</a>"));
+        assert!(html.contains("<a href=\"/a&amp;b/%3Cb%3E%26.txt\">&lt;b&gt;&amp;.txt</a></td><td class=\"size\">2.0 KiB</td>"));
+        // the active key toggles to descending, the others start ascending
+        assert!(html.contains("?sort=size&amp;order=desc"));
+        assert!(html.contains("?sort=name&amp;order=asc"));
+        assert!(!render_html("/", &entries, SortKey::Name, false).contains("../"));
+    }
+
+    #[test]
+    fn json_listing(){
+        let entries=[entry("sub",true,0,5),Entry{ name: "q\"uote.txt".to_owned(), is_dir: false, size: 7, modified: None }];
+        let doc: serde_json::Value=serde_json::from_str(&render_json("/x/", &entries)).unwrap();
+        assert_eq!(doc, json!({
+            "path": "/x/",
+            "entries": [
+                { "name": "sub", "type": "directory", "size": 0, "modified": 5 },
+                { "name": "q\"uote.txt", "type": "file", "size": 7, "modified": null },
+            ],
+        }));
+    }
+
+    #[test]
+    fn negotiates_json(){
+        let cases=[
+            (None,false),
+            (Some("application/json"),true),
+            (Some("application/json;q=0"),false),
+            (Some("application/json; q=0.0, text/html"),false),
+            (Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),false),
+            (Some("text/html;q=0.5, application/json"),true),
+            (Some("text/html, application/json;q=0.9"),false),
+            (Some("*/*"),false),
+        ];
+        for (accept,expected) in cases{
+            let header=accept.map(|a|vec![a.to_owned()]);
+            assert_eq!(wants_json(header.as_ref()), expected, "{accept:?}");
+        }
+        assert!(wants_json(Some(&vec!["text/plain".to_owned(),"application/json".to_owned()])));
+    }
+}
@@ -0,0 +1,182 @@
+use std::net::IpAddr;
+
+use rustls::{server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth, WantsServerCert}, Certificate, ConfigBuilder, RootCertStore, ServerConfig, WantsVerifier};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+use crate::{config::{ClientAuthConfig, ClientAuthMode, ClientRule}, tls};
+
+/// what the verified client certificate says about its owner
+#[derive(Debug, Clone)]
+pub struct ClientCert{
+    /// like `CN=deploy, O=Example`
+    pub subject: String,
+    pub common_name: Option<String>,
+    /// dns names, ip addresses, emails and uris
+    pub sans: Vec<String>,
+}
+
+impl ClientCert{
+    /// reads the leaf of a chain rustls already verified
+    pub fn from_chain(chain: &[Certificate])->Option<Self>{
+        let (_,cert)=X509Certificate::from_der(&chain.first()?.0).ok()?;
+        let subject=cert.subject().to_string();
+        let common_name=cert.subject().iter_common_name().next().and_then(|cn|cn.as_str().ok()).map(str::to_owned);
+        let mut sans=vec![];
+        if let Ok(Some(ext))=cert.subject_alternative_name(){
+            for name in &ext.value.general_names{
+                match name{
+                    GeneralName::DNSName(n) | GeneralName::RFC822Name(n) | GeneralName::URI(n)=>sans.push(n.to_string()),
+                    GeneralName::IPAddress(ip)=>match ip.len(){
+                        4=>sans.push(IpAddr::from(<[u8;4]>::try_from(*ip).ok()?).to_string()),
+                        16=>sans.push(IpAddr::from(<[u8;16]>::try_from(*ip).ok()?).to_string()),
+                        _=>(),
+                    },
+                    _=>(),
+                }
+            }
+        }
+        Some(Self{ subject, common_name, sans })
+    }
+}
+
+pub fn load_ca(path: &str)->Result<Vec<Certificate>,String>{
+    let certs=tls::load_certs(path).map_err(|e|format!("couldnt read client ca {path} {e:?}"))?;
+    if certs.is_empty() { return Err(format!("no certificates in client ca {path}")) }
+    Ok(certs)
+}
+
+/// asks for client certificates as `client_auth.mode` says, chained to `client_auth.ca_path`
+pub fn with_client_auth(builder: ConfigBuilder<ServerConfig,WantsVerifier>, config: &ClientAuthConfig)->Result<ConfigBuilder<ServerConfig,WantsServerCert>,String>{
+    if config.mode==ClientAuthMode::None { return Ok(builder.with_client_cert_verifier(NoClientAuth::new())) }
+    let mut roots=RootCertStore::empty();
+    for cert in load_ca(&config.ca_path)?{
+        roots.add(&cert).map_err(|e|format!("unusable client ca in {} {e:?}",config.ca_path))?;
+    }
+    Ok(builder.with_client_cert_verifier(match config.mode{
+        ClientAuthMode::Required=>AllowAnyAuthenticatedClient::new(roots),
+        _=>AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+    }))
+}
+
+fn rule_allows(rule: &ClientRule, cert: &ClientCert)->bool{
+    if rule.subjects.is_empty() && rule.sans.is_empty() { return true }
+    rule.subjects.iter().any(|s|*s==cert.subject || cert.common_name.as_ref()==Some(s))
+        || rule.sans.iter().any(|s|cert.sans.iter().any(|san|san.eq_ignore_ascii_case(s)))
+}
+
+// whole segments only, `/internal` doesnt cover `/internalfoo`. `/internal/` covers `/internal`
+// itself too, cleaned paths lose their trailing slash
+fn covers(rule: &ClientRule, path: &str)->bool{
+    let prefix=rule.path.trim_end_matches('/');
+    path.strip_prefix(prefix).is_some_and(|rest|rest.is_empty() || rest.starts_with('/'))
+}
+
+/// wether the first rule covering `path` lets `peer` in. paths without a rule are open to everyone
+pub fn allowed(rules: &[ClientRule], peer: Option<&ClientCert>, path: &str)->bool{
+    match rules.iter().find(|r|covers(r, path)){
+        Some(rule)=>peer.is_some_and(|cert|rule_allows(rule, cert)),
+        None=>true,
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, SanType};
+
+    fn rule(path: &str, subjects: &[&str], sans: &[&str])->ClientRule{
+        ClientRule{ path: path.to_owned(), subjects: subjects.iter().map(|s|s.to_string()).collect(), sans: sans.iter().map(|s|s.to_string()).collect() }
+    }
+
+    fn cert(subject: &str, common_name: Option<&str>, sans: &[&str])->ClientCert{
+        ClientCert{ subject: subject.to_owned(), common_name: common_name.map(str::to_owned), sans: sans.iter().map(|s|s.to_string()).collect() }
+    }
+
+    #[test]
+    fn rules_cover_whole_segments(){
+        let cases=[
+            ("/internal","/internal",true),
+            ("/internal","/internal/",true),
+            ("/internal","/internal/a/b",true),
+            ("/internal","/internalfoo",false),
+            ("/internal","/intern",false),
+            ("/internal/","/internal",true),
+            ("/internal/","/internal/a",true),
+            ("/internal/","/internalfoo",false),
+            ("/","/",true),
+            ("/","/anything",true),
+            ("/a/b","/a",false),
+        ];
+        for (path,request,expected) in cases{
+            assert_eq!(covers(&rule(path, &[], &[]), request), expected, "{path} covering {request}");
+        }
+    }
+
+    #[test]
+    fn first_covering_rule_decides(){
+        let rules=[
+            rule("/internal/public", &[], &[]),
+            rule("/internal", &["CN=deploy, O=Example"], &[]),
+            rule("/admin", &["admin"], &["ops.example.com","10.0.0.1"]),
+            rule("/any", &[], &[]),
+        ];
+        let deploy=cert("CN=deploy, O=Example", Some("deploy"), &[]);
+        let admin=cert("CN=admin, O=Other", Some("admin"), &[]);
+        let ops=cert("CN=someone", Some("someone"), &["OPS.example.com"]);
+        let cases=[
+            ("/index.html",None,true),
+            ("/internalfoo",None,true),
+            ("/internal/x",None,false),
+            ("/internal/x",Some(&deploy),true),
+            ("/internal/x",Some(&admin),false),
+            // the earlier rule without lists lets any verified certificate in
+            ("/internal/public/a",Some(&admin),true),
+            ("/internal/public/a",None,false),
+            ("/admin",Some(&admin),true),
+            ("/admin",Some(&ops),true),
+            ("/admin",Some(&deploy),false),
+            ("/any",Some(&deploy),true),
+            ("/any",None,false),
+        ];
+        for (path,peer,expected) in cases{
+            assert_eq!(allowed(&rules, peer, path), expected, "{path} for {peer:?}");
+        }
+        assert!(allowed(&[], None, "/internal"));
+    }
+
+    #[test]
+    fn reads_the_leaf(){
+        let key=KeyPair::generate().unwrap();
+        let mut params=CertificateParams::default();
+        let mut dn=DistinguishedName::new();
+        dn.push(DnType::CommonName, "deploy");
+        dn.push(DnType::OrganizationName, "Example");
+        params.distinguished_name=dn;
+        params.subject_alt_names=vec![
+            SanType::DnsName("ops.example.com".try_into().unwrap()),
+            SanType::IpAddress("10.0.0.1".parse().unwrap()),
+            SanType::IpAddress("::1".parse().unwrap()),
+            SanType::Rfc822Name("ops@example.com".try_into().unwrap()),
+            SanType::URI("spiffe://example/deploy".try_into().unwrap()),
+        ];
+        let leaf=params.self_signed(&key).unwrap();
+        let other=CertificateParams::new(vec!["other".to_owned()]).unwrap().self_signed(&KeyPair::generate().unwrap()).unwrap();
+
+        let parsed=ClientCert::from_chain(&[Certificate(leaf.der().to_vec()),Certificate(other.der().to_vec())]).unwrap();
+        assert_eq!(parsed.subject, "CN=deploy, O=Example");
+        assert_eq!(parsed.common_name.as_deref(), Some("deploy"));
+        assert_eq!(parsed.sans, ["ops.example.com","10.0.0.1","::1","ops@example.com","spiffe://example/deploy"]);
+
+        let mut params=CertificateParams::default();
+        params.distinguished_name=DistinguishedName::new();
+        params.distinguished_name.push(DnType::OrganizationName, "Example");
+        let bare=params.self_signed(&key).unwrap();
+        let parsed=ClientCert::from_chain(&[Certificate(bare.der().to_vec())]).unwrap();
+        assert_eq!(parsed.subject, "O=Example");
+        assert_eq!(parsed.common_name, None);
+        assert!(parsed.sans.is_empty());
+
+        assert!(ClientCert::from_chain(&[]).is_none());
+        assert!(ClientCert::from_chain(&[Certificate(b"not der".to_vec())]).is_none());
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+// media types that compress well even though their top level type usually doesnt
+const COMPRESSIBLE_EXCEPTIONS: [&str; 6]=[
+    "image/svg+xml",
+    "image/bmp",
+    "image/x-ms-bmp",
+    "image/x-icon",
+    "image/vnd.microsoft.icon",
+    "audio/wav",
+];
+
+// types that are already compressed internally
+const INCOMPRESSIBLE: [&str; 7]=[
+    "font/woff",
+    "font/woff2",
+    "application/font-woff",
+    "application/pdf",
+    "application/octet-stream",
+    "application/vnd.ms-fontobject",
+    "application/x-apple-diskimage",
+];
+
+/// whether `pattern` covers `mime`. patterns are exact types or `type/*`
+pub fn pattern_matches(pattern: &str, mime: &str)->bool{
+    let pattern=pattern.trim();
+    match pattern.strip_suffix("/*"){
+        Some(top)=>mime.split('/').next()==Some(top),
+        None=>pattern.eq_ignore_ascii_case(mime) || pattern=="*",
+    }
+}
+
+fn default_compressible(mime: &str)->bool{
+    if COMPRESSIBLE_EXCEPTIONS.contains(&mime) { return true }
+    if INCOMPRESSIBLE.contains(&mime) { return false }
+
+    let (top,sub)=mime.split_once('/').unwrap_or((mime,""));
+    if matches!(top,"image"|"video"|"audio") { return false }
+    // zip based containers like epub, jar and apk, and other archive formats
+    !(sub.contains("zip") || sub.contains("compressed") || sub.contains("bzip")
+        || matches!(sub,"vnd.rar"|"x-xz"|"zstd"|"x-lzma"|"x-lzip"|"vnd.android.package-archive"|"java-archive"))
+}
+
+/// builds the table of which content types from `mime_map` are worth compressing.
+/// `skip` and `allow` are lists of patterns that override the defaults, `allow` wins over `skip`
+pub fn compressible_map(mime: &HashMap<&'static str,&'static str>, skip: &[String], allow: &[String])->HashMap<&'static str,bool>{
+    let mut map=HashMap::new();
+    for ct in mime.values().copied().chain(INCOMPRESSIBLE){
+        let compressible=if allow.iter().any(|p|pattern_matches(p,ct)) { true }
+            else if skip.iter().any(|p|pattern_matches(p,ct)) { false }
+            else { default_compressible(ct) };
+        map.insert(ct,compressible);
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn patterns(){
+        let cases=[
+            ("text/html","text/html",true),
+            ("Text/HTML","text/html",true),
+            ("text/html","text/css",false),
+            ("text/*","text/css",true),
+            ("text/*","application/javascript",false),
+            (" image/* ","image/png",true),
+            ("image/*","imagery/png",false),
+            ("*","anything/at-all",true),
+            ("application/json","application/json+x",false),
+        ];
+        for (pattern,mime,expected) in cases{
+            assert_eq!(pattern_matches(pattern, mime), expected, "{pattern} against {mime}");
+        }
+    }
+
+    #[test]
+    fn defaults(){
+        let cases=[
+            ("text/html",true),
+            ("application/javascript",true),
+            ("application/json",true),
+            ("image/png",false),
+            ("video/mp4",false),
+            ("audio/mpeg",false),
+            // exceptions within image and audio
+            ("image/svg+xml",true),
+            ("image/x-icon",true),
+            ("audio/wav",true),
+            ("font/woff2",false),
+            ("application/pdf",false),
+            ("application/octet-stream",false),
+            ("application/zip",false),
+            ("application/epub+zip",false),
+            ("application/x-7z-compressed",false),
+            ("application/vnd.rar",false),
+            ("application/java-archive",false),
+        ];
+        for (mime,expected) in cases{
+            assert_eq!(default_compressible(mime), expected, "{mime}");
+        }
+    }
+
+    fn map(skip: &[&str], allow: &[&str])->HashMap<&'static str,bool>{
+        let mime: HashMap<&'static str,&'static str>=[("html","text/html"),("css","text/css"),("svg","image/svg+xml"),("png","image/png"),("json","application/json")].into_iter().collect();
+        let owned=|l: &[&str]|l.iter().map(|p|p.to_string()).collect::<Vec<_>>();
+        compressible_map(&mime, &owned(skip), &owned(allow))
+    }
+
+    #[test]
+    fn overrides(){
+        let defaults=map(&[], &[]);
+        assert!(defaults["text/html"]);
+        assert!(!defaults["image/png"]);
+        // the incompressible list is always in the table, even without a file extension for it
+        assert!(!defaults["font/woff2"]);
+
+        let skipped=map(&["text/*","image/svg+xml"], &[]);
+        assert_eq!((skipped["text/html"],skipped["text/css"],skipped["image/svg+xml"],skipped["application/json"]), (false,false,false,true));
+
+        let allowed=map(&[], &["image/png","application/pdf"]);
+        assert_eq!((allowed["image/png"],allowed["application/pdf"],allowed["image/svg+xml"]), (true,true,true));
+
+        // allow wins over skip, whichever is more specific
+        let both=map(&["text/*","image/*"], &["text/css","image/*"]);
+        assert_eq!((both["text/html"],both["text/css"],both["image/png"],both["image/svg+xml"]), (false,true,true,true));
+        let both=map(&["text/css"], &["text/*"]);
+        assert!(both["text/css"]);
+    }
+}
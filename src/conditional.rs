@@ -0,0 +1,218 @@
+use std::{collections::HashMap, fs::Metadata, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use sha2::{Digest, Sha256};
+use tokio::{fs::File, io::AsyncReadExt};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Precondition{
+    Proceed,
+    NotModified,
+    Failed,
+}
+
+/// modification time truncated to whole seconds, the resolution of an HTTP-date
+pub fn last_modified(meta: &Metadata)->Option<SystemTime>{
+    let secs=meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(UNIX_EPOCH+Duration::from_secs(secs))
+}
+
+pub fn weak_etag(meta: &Metadata)->String{
+    let mtime=meta.modified().ok()
+        .and_then(|m|m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0,|d|d.as_nanos());
+    format!("W/\"{:x}-{:x}\"",mtime,meta.len())
+}
+
+pub async fn strong_etag(path: &str)->std::io::Result<String>{
+    let mut file=File::open(path).await?;
+    let mut hasher=Sha256::new();
+    let mut buffer=vec![0; 64*1024];
+    loop{
+        let n=file.read(&mut buffer).await?;
+        if n==0 { break }
+        hasher.update(&buffer[..n]);
+    }
+    let hash=hasher.finalize();
+    // 128 bits is plenty to tell two versions of a file apart
+    let hex: String=hash[..16].iter().map(|b|format!("{:02x}",b)).collect();
+    Ok(format!("\"{}\"",hex))
+}
+
+/// derives the tag of an on the fly encoded representation, which must differ from the identity one
+pub fn with_coding(etag: &str, coding: &str)->String{
+    match etag.strip_suffix('"'){
+        Some(t)=>format!("{}-{}\"",t,coding),
+        None=>etag.to_owned(),
+    }
+}
+
+fn is_weak(tag: &str)->bool{
+    tag.starts_with("W/")
+}
+
+fn opaque(tag: &str)->&str{
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+pub fn strong_match(a: &str, b: &str)->bool{
+    !is_weak(a) && !is_weak(b) && a==b
+}
+
+pub fn weak_match(a: &str, b: &str)->bool{
+    opaque(a)==opaque(b)
+}
+
+fn header(headers: &HashMap<String,Vec<String>>, name: &str)->Option<String>{
+    headers.get(name).map(|v|v.join(","))
+}
+
+fn parse_date(value: &str)->Option<SystemTime>{
+    httpdate::parse_http_date(value.trim()).ok()
+}
+
+/// splits an entity tag list. commas can legally appear inside a quoted tag so this cant be a plain split
+fn tag_list(value: &str)->Vec<String>{
+    let mut tags=vec![];
+    let mut current=String::new();
+    let mut quoted=false;
+    for c in value.chars(){
+        match c{
+            '"'=>{ quoted=!quoted; current.push(c) },
+            ',' if !quoted=>{
+                if !current.trim().is_empty() { tags.push(current.trim().to_owned()) }
+                current.clear();
+            },
+            _=>current.push(c),
+        }
+    }
+    if !current.trim().is_empty() { tags.push(current.trim().to_owned()) }
+    tags
+}
+
+fn any_tag(value: &str, etag: &str, cmp: fn(&str,&str)->bool)->bool{
+    value.trim()=="*" || tag_list(value).iter().any(|t|cmp(t,etag))
+}
+
+/// evaluates the preconditions of RFC 9110 section 13.2.2 in order.
+/// `Range`/`If-Range` are handled by the caller after this returns `Proceed`
+pub fn evaluate(method: &str, headers: &HashMap<String,Vec<String>>, etag: &str, modified: Option<SystemTime>)->Precondition{
+    let safe=matches!(method,"GET"|"HEAD");
+
+    if let Some(im)=header(headers,"if-match"){
+        if !any_tag(&im, etag, strong_match) { return Precondition::Failed }
+    } else if let Some(ius)=header(headers,"if-unmodified-since"){
+        if let (Some(date),Some(modified))=(parse_date(&ius),modified){
+            if modified>date { return Precondition::Failed }
+        }
+    }
+
+    if let Some(inm)=header(headers,"if-none-match"){
+        if any_tag(&inm, etag, weak_match) {
+            return if safe { Precondition::NotModified } else { Precondition::Failed };
+        }
+    } else if let (true,Some(ims))=(safe,header(headers,"if-modified-since")){
+        if let (Some(date),Some(modified))=(parse_date(&ims),modified){
+            if modified<=date { return Precondition::NotModified }
+        }
+    }
+
+    Precondition::Proceed
+}
+
+pub type EtagCache=Arc<Mutex<HashMap<String,(Option<SystemTime>,u64,String)>>>;
+
+/// returns the entity tag for a file. with a cache present content hashes are used as strong tags,
+/// which are only recomputed once the files mtime or size changes
+pub async fn entity_tag(cache: Option<&EtagCache>, path: &str, meta: &Metadata)->String{
+    let cache=match cache{
+        Some(c)=>c,
+        None=>return weak_etag(meta),
+    };
+    let modified=meta.modified().ok();
+    if let Some((m,len,tag))=cache.lock().unwrap().get(path){
+        if *m==modified && *len==meta.len() { return tag.clone() }
+    }
+    match strong_etag(path).await{
+        Ok(tag)=>{
+            cache.lock().unwrap().insert(path.to_owned(), (modified,meta.len(),tag.clone()));
+            tag
+        },
+        Err(e)=>{
+            eprintln!("couldnt hash {path}, falling back to weak etag {e:?}");
+            weak_etag(meta)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn headers(list: &[(&str,&str)])->HashMap<String,Vec<String>>{
+        let mut headers: HashMap<String,Vec<String>>=HashMap::new();
+        for (name,value) in list{ headers.entry(name.to_string()).or_default().push(value.to_string()) }
+        headers
+    }
+
+    fn date(s: &str)->Option<SystemTime>{
+        httpdate::parse_http_date(s).ok()
+    }
+
+    #[test]
+    fn etag_comparison(){
+        assert!(strong_match("\"1\"", "\"1\""));
+        assert!(!strong_match("W/\"1\"", "\"1\""));
+        assert!(!strong_match("W/\"1\"", "W/\"1\""));
+        assert!(!strong_match("\"1\"", "\"2\""));
+        assert!(weak_match("W/\"1\"", "\"1\""));
+        assert!(weak_match("W/\"1\"", "W/\"1\""));
+        assert!(!weak_match("W/\"1\"", "W/\"2\""));
+    }
+
+    #[test]
+    fn tag_lists(){
+        assert_eq!(tag_list("\"a\", W/\"b\",\"c,d\""), ["\"a\"","W/\"b\"","\"c,d\""]);
+        assert_eq!(tag_list(" , "), Vec::<String>::new());
+    }
+
+    #[test]
+    fn coded_tags_differ(){
+        assert_eq!(with_coding("\"abc\"", "gzip"), "\"abc-gzip\"");
+        assert_eq!(with_coding("W/\"abc\"", "br"), "W/\"abc-br\"");
+    }
+
+    #[test]
+    fn if_none_match_is_weak(){
+        let etag="W/\"5-a\"";
+        assert_eq!(evaluate("GET", &headers(&[("if-none-match","W/\"5-a\"")]), etag, None), Precondition::NotModified);
+        assert_eq!(evaluate("GET", &headers(&[("if-none-match","\"5-a\"")]), etag, None), Precondition::NotModified);
+        assert_eq!(evaluate("HEAD", &headers(&[("if-none-match","\"x\", W/\"5-a\"")]), etag, None), Precondition::NotModified);
+        assert_eq!(evaluate("GET", &headers(&[("if-none-match","*")]), etag, None), Precondition::NotModified);
+        assert_eq!(evaluate("GET", &headers(&[("if-none-match","\"other\"")]), etag, None), Precondition::Proceed);
+        assert_eq!(evaluate("PUT", &headers(&[("if-none-match","*")]), etag, None), Precondition::Failed);
+    }
+
+    #[test]
+    fn if_match_is_strong(){
+        assert_eq!(evaluate("GET", &headers(&[("if-match","\"a\"")]), "\"a\"", None), Precondition::Proceed);
+        assert_eq!(evaluate("GET", &headers(&[("if-match","W/\"a\"")]), "W/\"a\"", None), Precondition::Failed);
+        assert_eq!(evaluate("GET", &headers(&[("if-match","*")]), "W/\"a\"", None), Precondition::Proceed);
+        assert_eq!(evaluate("GET", &headers(&[("if-match","\"b\"")]), "\"a\"", None), Precondition::Failed);
+    }
+
+    #[test]
+    fn dates(){
+        let modified=date("Wed, 21 Oct 2015 07:28:00 GMT");
+        let since=|h: &str, v: &str|evaluate("GET", &headers(&[(h,v)]), "\"a\"", modified);
+        assert_eq!(since("if-modified-since","Wed, 21 Oct 2015 07:28:00 GMT"), Precondition::NotModified);
+        assert_eq!(since("if-modified-since","Wed, 21 Oct 2015 07:27:59 GMT"), Precondition::Proceed);
+        assert_eq!(since("if-modified-since","not a date"), Precondition::Proceed);
+        assert_eq!(since("if-unmodified-since","Wed, 21 Oct 2015 07:27:59 GMT"), Precondition::Failed);
+        assert_eq!(since("if-unmodified-since","Wed, 21 Oct 2015 07:28:00 GMT"), Precondition::Proceed);
+        // an entity tag condition takes precedence over the date
+        let both=headers(&[("if-none-match","\"b\""),("if-modified-since","Wed, 21 Oct 2015 07:28:00 GMT")]);
+        assert_eq!(evaluate("GET", &both, "\"a\"", modified), Precondition::Proceed);
+        // only safe methods get a 304 from a date
+        assert_eq!(evaluate("POST", &headers(&[("if-modified-since","Wed, 21 Oct 2015 07:28:00 GMT")]), "\"a\"", modified), Precondition::Proceed);
+    }
+}
@@ -0,0 +1,1062 @@
+use std::{collections::BTreeMap, env, fmt, fs, io::{Error, ErrorKind}, net::ToSocketAddrs, path::Path};
+
+use rust_http::http2::Http2FrameSettings;
+use serde::Deserialize;
+
+use crate::{middleware, tls::is_pkcs12};
+
+pub const DEFAULT_CONFIG_PATH: &str = "static-serve.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config{
+    pub serve_dir: String,
+    pub autoindex: bool,
+    /// apply `_redirects` and `_headers` from the serve directory
+    pub site_files: bool,
+    pub strong_etag: bool,
+    pub listener: ListenerConfig,
+    pub redirect: RedirectConfig,
+    pub tls: TlsConfig,
+    pub client_auth: ClientAuthConfig,
+    pub acme: AcmeConfig,
+    pub http1: Http1Config,
+    pub http2: Http2Config,
+    pub http3: Http3Config,
+    pub compression: CompressionConfig,
+    /// the preset every response starts from
+    pub security_headers: SecurityHeadersConfig,
+    /// added to every static response, replacing preset headers of the same name
+    pub headers: BTreeMap<String,String>,
+    /// `[[header_rules]]`, applied in order after `headers`
+    pub header_rules: Vec<HeaderRule>,
+    pub middleware: MiddlewareConfig,
+    pub shutdown: ShutdownConfig,
+    /// `[[vhosts]]` tables, hosts not listed get `serve_dir` and the `[tls]` certificate
+    pub vhosts: Vec<VhostConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig{
+    /// used when there are no `endpoints`
+    pub address: String,
+    /// `[[listener.endpoints]]`, every one feeds the same handlers
+    pub endpoints: Vec<ListenEndpoint>,
+}
+
+/// one of `address`, `unix` or `systemd`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenEndpoint{
+    /// tcp address. `[::]:port` takes ipv4 connections as well unless `ipv6_only`
+    pub address: Option<String>,
+    pub ipv6_only: bool,
+    /// unix socket path, a stale socket file there is replaced
+    pub unix: Option<String>,
+    /// octal permissions of the unix socket like `"660"`
+    pub mode: Option<String>,
+    /// every socket systemd passed through LISTEN_FDS
+    pub systemd: bool,
+    /// tls on this endpoint, `tls.enabled` when unset
+    pub tls: Option<bool>,
+    /// http/2 on this endpoint, `http2.enabled` when unset. it can only be turned off here
+    pub http2: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedirectConfig{
+    /// plain http address like `0.0.0.0:80` that only redirects to https, off when unset
+    pub address: Option<String>,
+    /// 301, 302, 307 or 308. the 307/308 ones keep the method
+    pub status: u16,
+    /// port in the redirect url, the tls listener's when unset. 443 is left out
+    pub https_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig{
+    /// when enabled but the key or certificate cant be loaded the server falls back to plain tcp
+    pub enabled: bool,
+    /// pem key, or a `.p12`/`.pfx` bundle that also holds the certificates
+    pub key_path: String,
+    pub cert_path: String,
+    pub pkcs12_password: Secret,
+    /// seconds between checks for renewed key or certificate files, 0 leaves it to SIGHUP
+    pub reload_interval: u64,
+    /// serve a generated self signed certificate instead of key_path and cert_path
+    pub dev: bool,
+    /// names the development certificate covers besides localhost, 127.0.0.1, ::1 and the vhosts
+    pub dev_names: Vec<String>,
+    /// keep the development certificate in key_path and cert_path so browsers only need to trust it once
+    pub dev_persist: bool,
+}
+
+/// a string kept out of `--check-config` output
+#[derive(Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        if self.0.is_empty() { f.write_str("\"\"") } else { f.write_str("\"***\"") }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcmeConfig{
+    /// obtain and renew the default certificate from `directory_url` instead of reading key_path and cert_path
+    pub enabled: bool,
+    pub directory_url: String,
+    /// names the certificate is issued for, the first one becomes the subject
+    pub domains: Vec<String>,
+    /// `mailto:` addresses the ca may send expiry notices to
+    pub contact: Vec<String>,
+    /// account key, certificate and its key are kept here between restarts
+    pub state_dir: String,
+    pub challenge: AcmeChallenge,
+    /// pem bundle trusted for the directory connection, the system bundle when unset.
+    /// pebble needs its own minica root here
+    pub ca_path: Option<String>,
+    /// renew once the certificate expires in less than this many days
+    pub renew_days: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum AcmeChallenge{
+    /// `/.well-known/acme-challenge/` answered by the http listener, the ca connects to port 80
+    #[serde(rename = "http-01")]
+    Http01,
+    /// a special certificate during the tls handshake, the ca connects to port 443
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthMode{
+    /// no certificate is asked for
+    None,
+    /// a certificate is asked for, connections without one still go through
+    Optional,
+    /// the handshake fails without a certificate signed by `ca_path`
+    Required,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientAuthConfig{
+    pub mode: ClientAuthMode,
+    /// pem bundle of the CAs client certificates have to chain to
+    pub ca_path: String,
+    /// `[[client_auth.rules]]`, the first rule whose path prefix matches decides
+    pub rules: Vec<ClientRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientRule{
+    /// path prefix like `/internal/`
+    pub path: String,
+    /// subject DNs (`CN=deploy, O=Example`) or just common names that are let in
+    #[serde(default)]
+    pub subjects: Vec<String>,
+    /// dns names, ip addresses or emails from the subject alt names that are let in.
+    /// with neither list any verified certificate is enough
+    #[serde(default)]
+    pub sans: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderPreset{
+    /// HSTS (with tls), nosniff, a same origin CSP and Referrer-Policy
+    Secure,
+    None,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityHeadersConfig{
+    pub preset: HeaderPreset,
+    /// Strict-Transport-Security max-age in seconds, 0 leaves the header out
+    pub hsts_max_age: u64,
+    pub hsts_include_subdomains: bool,
+    pub content_security_policy: String,
+    pub referrer_policy: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRule{
+    /// glob over the request path, `*` stays within a segment and `**` crosses them. every path without one
+    pub path: Option<String>,
+    /// response mime types or `type/*`, any type when empty
+    #[serde(default)]
+    pub mime: Vec<String>,
+    /// header names taken out of the response
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// headers added, replacing ones of the same name
+    #[serde(default)]
+    pub set: BTreeMap<String,String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http1Config{
+    pub keep_alive: bool,
+    /// seconds an idle connection waits for its next request
+    pub keep_alive_timeout: u64,
+    /// requests served on one connection before it is closed
+    pub max_requests: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http2Config{
+    pub enabled: bool,
+    /// offer h2 before http/1.1 in alpn
+    pub first: bool,
+    /// hpack table the client may use for headers it sends, also sizes our decoder
+    pub header_table_size: Option<u32>,
+    /// flow control window of every stream
+    pub initial_window_size: Option<u32>,
+    pub max_frame_size: Option<u32>,
+    pub max_header_list_size: Option<u32>,
+    /// streams past this are refused with RST_STREAM
+    pub max_concurrent_streams: Option<u32>,
+    /// how much the client may send us over the whole connection before waiting, raised with
+    /// a WINDOW_UPDATE on stream 0. it only helps uploads, the window for responses is the clients
+    pub connection_window_size: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http3Config{
+    /// serve http/3 over quic on the udp port matching the tls listener, needs tls
+    pub enabled: bool,
+    /// seconds clients may remember the `Alt-Svc` advertisement
+    pub alt_svc_max_age: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig{
+    pub min_size: u64,
+    /// mime types (or `type/*`) that are never compressed on the fly
+    pub skip: Vec<String>,
+    /// mime types (or `type/*`) that are always compressed, wins over `skip`
+    pub allow: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MiddlewareConfig{
+    /// names of the endpoints from `middleware::NAMES` that are served
+    pub enabled: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VhostConfig{
+    /// hostnames, `*.example.com` covers every direct subdomain
+    pub hosts: Vec<String>,
+    pub serve_dir: String,
+    /// picked by sni. without them the `[tls]` pair is used for these hosts too
+    #[serde(default)]
+    pub key_path: Option<String>,
+    #[serde(default)]
+    pub cert_path: Option<String>,
+    /// for a `.p12` key_path, `tls.pkcs12_password` when unset
+    #[serde(default)]
+    pub pkcs12_password: Option<Secret>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig{
+    /// seconds to wait for in flight connections after SIGINT/SIGTERM
+    pub drain_timeout: u64,
+}
+
+impl Default for Config{
+    fn default()->Self{
+        Self{
+            serve_dir: "./public".to_owned(),
+            autoindex: false,
+            site_files: false,
+            strong_etag: false,
+            listener: ListenerConfig::default(),
+            redirect: RedirectConfig::default(),
+            tls: TlsConfig::default(),
+            client_auth: ClientAuthConfig::default(),
+            acme: AcmeConfig::default(),
+            http1: Http1Config::default(),
+            http2: Http2Config::default(),
+            http3: Http3Config::default(),
+            compression: CompressionConfig::default(),
+            security_headers: SecurityHeadersConfig::default(),
+            headers: BTreeMap::new(),
+            header_rules: vec![],
+            middleware: MiddlewareConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            vhosts: vec![],
+        }
+    }
+}
+impl Default for SecurityHeadersConfig{
+    fn default()->Self{
+        Self{
+            // opt in, the CSP breaks inline scripts and CDNs and HSTS pins browsers to https
+            preset: HeaderPreset::None,
+            hsts_max_age: 31536000,
+            hsts_include_subdomains: false,
+            // inline styles for the autoindex listing
+            content_security_policy: "default-src 'self'; style-src 'self' 'unsafe-inline'; object-src 'none'; frame-ancestors 'self'".to_owned(),
+            referrer_policy: "strict-origin-when-cross-origin".to_owned(),
+        }
+    }
+}
+impl Default for ListenerConfig{
+    fn default()->Self{
+        Self{ address: "0.0.0.0:8000".to_owned(), endpoints: vec![] }
+    }
+}
+impl Default for RedirectConfig{
+    fn default()->Self{
+        Self{ address: None, status: 308, https_port: None }
+    }
+}
+impl Default for TlsConfig{
+    fn default()->Self{
+        Self{
+            enabled: true,
+            key_path: "localhost.key".to_owned(),
+            cert_path: "localhost.crt".to_owned(),
+            pkcs12_password: Secret::default(),
+            reload_interval: 10,
+            dev: false,
+            dev_names: vec![],
+            dev_persist: false,
+        }
+    }
+}
+impl Default for AcmeConfig{
+    fn default()->Self{
+        Self{
+            enabled: false,
+            directory_url: "https://acme-v02.api.letsencrypt.org/directory".to_owned(),
+            domains: vec![],
+            contact: vec![],
+            state_dir: "acme".to_owned(),
+            challenge: AcmeChallenge::Http01,
+            ca_path: None,
+            renew_days: 30,
+        }
+    }
+}
+impl Default for ClientAuthConfig{
+    fn default()->Self{
+        Self{ mode: ClientAuthMode::None, ca_path: "client-ca.crt".to_owned(), rules: vec![] }
+    }
+}
+impl Default for Http1Config{
+    fn default()->Self{
+        Self{ keep_alive: true, keep_alive_timeout: 5, max_requests: 100 }
+    }
+}
+impl Default for Http2Config{
+    fn default()->Self{
+        Self{
+            enabled: true,
+            first: true,
+            header_table_size: Some(16777215),
+            initial_window_size: Some(65535),
+            max_frame_size: Some(65535),
+            max_header_list_size: None,
+            max_concurrent_streams: None,
+            connection_window_size: None,
+        }
+    }
+}
+impl Default for Http3Config{
+    fn default()->Self{
+        Self{ enabled: false, alt_svc_max_age: 86400 }
+    }
+}
+impl Default for CompressionConfig{
+    fn default()->Self{
+        Self{ min_size: 1024, skip: vec![], allow: vec![] }
+    }
+}
+impl Default for MiddlewareConfig{
+    fn default()->Self{
+        Self{ enabled: middleware::NAMES.iter().map(|n|n.to_string()).collect() }
+    }
+}
+
+impl Default for ShutdownConfig{
+    fn default()->Self{
+        Self{ drain_timeout: 30 }
+    }
+}
+
+impl Http2Config{
+    pub fn settings(&self)->Http2FrameSettings{
+        Http2FrameSettings{
+            header_table_size: self.header_table_size,
+            enable_push: None,
+            max_concurrent_streams: self.max_concurrent_streams,
+            initial_window_size: self.initial_window_size,
+            max_frame_size: self.max_frame_size,
+            max_header_list_size: self.max_header_list_size,
+        }
+    }
+}
+
+impl ListenEndpoint{
+    /// `unix:/run/static-serve.sock`, `systemd` or a tcp address, as given to `--listen`
+    pub fn parse(spec: &str)->Self{
+        match spec{
+            "systemd"=>Self{ systemd: true, ..Self::default() },
+            s=>match s.strip_prefix("unix:"){
+                Some(path)=>Self{ unix: Some(path.to_owned()), ..Self::default() },
+                None=>Self{ address: Some(s.to_owned()), ..Self::default() },
+            },
+        }
+    }
+
+    pub fn name(&self)->String{
+        match (&self.address,&self.unix){
+            (Some(a),_)=>a.clone(),
+            (None,Some(p))=>format!("unix:{p}"),
+            _=>"systemd".to_owned(),
+        }
+    }
+}
+
+impl HeaderPreset{
+    pub fn parse(v: &str)->Option<Self>{
+        match v.to_lowercase().as_str(){
+            "secure"=>Some(Self::Secure),
+            "none"=>Some(Self::None),
+            _=>None,
+        }
+    }
+}
+
+impl ClientAuthMode{
+    pub fn parse(v: &str)->Option<Self>{
+        match v.to_lowercase().as_str(){
+            "none" | "off"=>Some(Self::None),
+            "optional"=>Some(Self::Optional),
+            "required" | "require"=>Some(Self::Required),
+            _=>None,
+        }
+    }
+}
+
+/// what was passed on the command line. every `Some` overrides env and the config file
+#[derive(Debug, Default)]
+pub struct Args{
+    pub config: Option<String>,
+    pub check_config: bool,
+    pub help: bool,
+    pub address: Option<String>,
+    pub redirect_from: Option<String>,
+    pub listen: Vec<ListenEndpoint>,
+    pub serve_dir: Option<String>,
+    pub key_path: Option<String>,
+    pub cert_path: Option<String>,
+    pub tls: Option<bool>,
+    pub dev_tls: Option<bool>,
+    pub client_auth: Option<ClientAuthMode>,
+    pub client_ca: Option<String>,
+    pub http2: Option<bool>,
+    pub h2_first: Option<bool>,
+    pub http3: Option<bool>,
+    pub autoindex: Option<bool>,
+    pub site_files: Option<bool>,
+    pub strong_etag: Option<bool>,
+    pub compress_min_size: Option<u64>,
+    pub drain_timeout: Option<u64>,
+    pub keep_alive: Option<bool>,
+    pub header_preset: Option<HeaderPreset>,
+}
+
+fn invalid(msg: String)->Error{
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+fn parse_bool(v: &str)->Option<bool>{
+    match v.to_lowercase().as_str(){
+        "yes" | "y" | "1" | "true" | "on" => Some(true),
+        "no" | "n" | "0" | "false" | "off" => Some(false),
+        _=>None,
+    }
+}
+
+fn env_bool(name: &str)->Option<bool>{
+    env::var(name).ok().map(|v|parse_bool(&v).unwrap_or(false))
+}
+
+fn env_list(name: &str)->Option<Vec<String>>{
+    env::var(name).ok().map(|v|v.split(',').map(|s|s.trim().to_owned()).filter(|s|!s.is_empty()).collect())
+}
+
+impl Args{
+    /// parses `--flag value` and `--flag=value`. up to four positional arguments are still
+    /// accepted as address, directory, key and certificate for compatibility with older invocations
+    pub fn parse(args: &[String])->std::io::Result<Self>{
+        let mut parsed=Args::default();
+        let mut positional=vec![];
+        let mut iter=args.iter().skip(1);
+
+        while let Some(arg)=iter.next(){
+            if !arg.starts_with('-') {
+                positional.push(arg.clone());
+                continue;
+            }
+            let (flag,inline)=match arg.split_once('='){
+                Some((f,v))=>(f,Some(v.to_owned())),
+                None=>(arg.as_str(),None),
+            };
+            let mut value=||->std::io::Result<String>{
+                match &inline{
+                    Some(v)=>Ok(v.clone()),
+                    None=>iter.next().cloned().ok_or_else(||invalid(format!("{flag} needs a value"))),
+                }
+            };
+            let switch=|default: bool|->std::io::Result<bool>{
+                match &inline{
+                    Some(v)=>parse_bool(v).ok_or_else(||invalid(format!("{flag} expects a boolean, got {v}"))),
+                    None=>Ok(default),
+                }
+            };
+
+            match flag{
+                "-h" | "--help"=>parsed.help=true,
+                "-c" | "--config"=>parsed.config=Some(value()?),
+                "--check-config"=>parsed.check_config=true,
+                "-a" | "--address"=>parsed.address=Some(value()?),
+                "-l" | "--listen"=>parsed.listen.push(ListenEndpoint::parse(&value()?)),
+                "--redirect-from"=>parsed.redirect_from=Some(value()?),
+                "-d" | "--serve-dir"=>parsed.serve_dir=Some(value()?),
+                "--key"=>parsed.key_path=Some(value()?),
+                "--cert"=>parsed.cert_path=Some(value()?),
+                "--tls"=>parsed.tls=Some(switch(true)?),
+                "--no-tls"=>parsed.tls=Some(!switch(true)?),
+                "--dev-tls"=>parsed.dev_tls=Some(switch(true)?),
+                "--client-auth"=>{
+                    let v=value()?;
+                    parsed.client_auth=Some(ClientAuthMode::parse(&v).ok_or_else(||invalid(format!("{flag} expects none, optional or required, got {v}")))?);
+                },
+                "--client-ca"=>parsed.client_ca=Some(value()?),
+                "--http2"=>parsed.http2=Some(switch(true)?),
+                "--no-http2"=>parsed.http2=Some(!switch(true)?),
+                "--h2-first"=>parsed.h2_first=Some(switch(true)?),
+                "--http3"=>parsed.http3=Some(switch(true)?),
+                "--no-http3"=>parsed.http3=Some(!switch(true)?),
+                "--autoindex"=>parsed.autoindex=Some(switch(true)?),
+                "--site-files"=>parsed.site_files=Some(switch(true)?),
+                "--strong-etag"=>parsed.strong_etag=Some(switch(true)?),
+                "--compress-min-size"=>{
+                    let v=value()?;
+                    parsed.compress_min_size=Some(v.parse().map_err(|_|invalid(format!("{flag} expects a number, got {v}")))?);
+                },
+                "--header-preset"=>{
+                    let v=value()?;
+                    parsed.header_preset=Some(HeaderPreset::parse(&v).ok_or_else(||invalid(format!("{flag} expects secure or none, got {v}")))?);
+                },
+                "--keep-alive"=>parsed.keep_alive=Some(switch(true)?),
+                "--no-keep-alive"=>parsed.keep_alive=Some(!switch(true)?),
+                "--drain-timeout"=>{
+                    let v=value()?;
+                    parsed.drain_timeout=Some(v.parse().map_err(|_|invalid(format!("{flag} expects a number of seconds, got {v}")))?);
+                },
+                _=>return Err(invalid(format!("unknown flag {flag}"))),
+            }
+        }
+
+        if positional.len()>4 {
+            return Err(invalid(format!("too many positional arguments {:?}, use named flags instead",positional)));
+        }
+        let mut positional=positional.into_iter();
+        if let Some(a)=positional.next() { parsed.address.get_or_insert(a); }
+        if let Some(d)=positional.next() { parsed.serve_dir.get_or_insert(d); }
+        if let Some(k)=positional.next() { parsed.key_path.get_or_insert(k); }
+        if let Some(c)=positional.next() { parsed.cert_path.get_or_insert(c); }
+
+        Ok(parsed)
+    }
+}
+
+impl Config{
+    pub fn from_file(path: &str)->std::io::Result<Self>{
+        let text=fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|e|Error::new(ErrorKind::InvalidData, format!("{path}: {e}")))
+    }
+
+    /// builds the effective config. precedence is flags > env > file > defaults.
+    /// without `--config` the file comes from `CONFIG` or `static-serve.toml` when that exists
+    pub fn load(args: &Args)->std::io::Result<Self>{
+        let path=args.config.clone().or_else(||env::var("CONFIG").ok());
+        let mut config=match path{
+            Some(p)=>Config::from_file(&p)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).is_file()=>Config::from_file(DEFAULT_CONFIG_PATH)?,
+            None=>Config::default(),
+        };
+        config.apply_env();
+        config.apply_args(args);
+        Ok(config)
+    }
+
+    fn apply_env(&mut self){
+        if let Ok(v)=env::var("SERVE_DIR") { self.serve_dir=v }
+        if let Ok(v)=env::var("ADDRESS") { self.listener.address=v }
+        if let Some(v)=env_list("LISTEN") { self.listener.endpoints=v.iter().map(|s|ListenEndpoint::parse(s)).collect() }
+        if let Ok(v)=env::var("REDIRECT_ADDRESS") { self.redirect.address=Some(v) }
+        if let Ok(v)=env::var("KEY_PATH") { self.tls.key_path=v }
+        if let Ok(v)=env::var("CERT_PATH") { self.tls.cert_path=v }
+        if let Ok(v)=env::var("PKCS12_PASSWORD") { self.tls.pkcs12_password=Secret(v) }
+        if let Some(v)=env_bool("USE_TLS") { self.tls.enabled=v }
+        if let Some(v)=env::var("TLS_RELOAD_INTERVAL").ok().and_then(|v|v.parse().ok()) { self.tls.reload_interval=v }
+        if let Some(v)=env_bool("ACME") { self.acme.enabled=v }
+        if let Ok(v)=env::var("ACME_DIRECTORY") { self.acme.directory_url=v }
+        if let Some(v)=env_list("ACME_DOMAINS") { self.acme.domains=v }
+        if let Some(v)=env_list("ACME_CONTACT") { self.acme.contact=v }
+        if let Ok(v)=env::var("ACME_STATE_DIR") { self.acme.state_dir=v }
+        if let Ok(v)=env::var("ACME_CA_PATH") { self.acme.ca_path=Some(v) }
+        if let Some(v)=env::var("CLIENT_AUTH").ok().and_then(|v|ClientAuthMode::parse(&v)) { self.client_auth.mode=v }
+        if let Ok(v)=env::var("CLIENT_CA_PATH") { self.client_auth.ca_path=v }
+        if let Some(v)=env_bool("DEV_TLS") { self.tls.dev=v }
+        if let Some(v)=env_list("DEV_TLS_NAMES") { self.tls.dev_names=v }
+        if let Some(v)=env_bool("DEV_TLS_PERSIST") { self.tls.dev_persist=v }
+        if let Some(v)=env_bool("ALLOW_HTTP2") { self.http2.enabled=v }
+        if let Some(v)=env_bool("H2_FIRST") { self.http2.first=v }
+        if let Some(v)=env_bool("ALLOW_HTTP3") { self.http3.enabled=v }
+        if let Some(v)=env_bool("STRONG_ETAG") { self.strong_etag=v }
+        if let Some(v)=env_bool("AUTOINDEX") { self.autoindex=v }
+        if let Some(v)=env_bool("SITE_FILES") { self.site_files=v }
+        if let Some(v)=env::var("COMPRESS_MIN_SIZE").ok().and_then(|v|v.parse().ok()) { self.compression.min_size=v }
+        if let Some(v)=env_list("COMPRESS_SKIP") { self.compression.skip=v }
+        if let Some(v)=env_list("COMPRESS_ALLOW") { self.compression.allow=v }
+        if let Some(v)=env_bool("KEEP_ALIVE") { self.http1.keep_alive=v }
+        if let Some(v)=env::var("HEADER_PRESET").ok().and_then(|v|HeaderPreset::parse(&v)) { self.security_headers.preset=v }
+        if let Some(v)=env::var("KEEP_ALIVE_TIMEOUT").ok().and_then(|v|v.parse().ok()) { self.http1.keep_alive_timeout=v }
+        if let Some(v)=env::var("MAX_REQUESTS").ok().and_then(|v|v.parse().ok()) { self.http1.max_requests=v }
+        if let Some(v)=env::var("H2_HEADER_TABLE_SIZE").ok().and_then(|v|v.parse().ok()) { self.http2.header_table_size=Some(v) }
+        if let Some(v)=env::var("H2_INITIAL_WINDOW_SIZE").ok().and_then(|v|v.parse().ok()) { self.http2.initial_window_size=Some(v) }
+        if let Some(v)=env::var("H2_MAX_FRAME_SIZE").ok().and_then(|v|v.parse().ok()) { self.http2.max_frame_size=Some(v) }
+        if let Some(v)=env::var("H2_MAX_HEADER_LIST_SIZE").ok().and_then(|v|v.parse().ok()) { self.http2.max_header_list_size=Some(v) }
+        if let Some(v)=env::var("H2_MAX_CONCURRENT_STREAMS").ok().and_then(|v|v.parse().ok()) { self.http2.max_concurrent_streams=Some(v) }
+        if let Some(v)=env::var("H2_CONNECTION_WINDOW_SIZE").ok().and_then(|v|v.parse().ok()) { self.http2.connection_window_size=Some(v) }
+        if let Some(v)=env::var("DRAIN_TIMEOUT").ok().and_then(|v|v.parse().ok()) { self.shutdown.drain_timeout=v }
+    }
+
+    fn apply_args(&mut self, args: &Args){
+        if let Some(v)=&args.serve_dir { self.serve_dir=v.clone() }
+        if let Some(v)=&args.address { self.listener.address=v.clone() }
+        if !args.listen.is_empty() { self.listener.endpoints=args.listen.clone() }
+        if let Some(v)=&args.redirect_from { self.redirect.address=Some(v.clone()) }
+        if let Some(v)=&args.key_path { self.tls.key_path=v.clone() }
+        if let Some(v)=&args.cert_path { self.tls.cert_path=v.clone() }
+        if let Some(v)=args.tls { self.tls.enabled=v }
+        if let Some(v)=args.dev_tls {
+            self.tls.dev=v;
+            // --dev-tls alone is enough to get https
+            if v && args.tls.is_none() { self.tls.enabled=true }
+        }
+        if let Some(v)=args.client_auth { self.client_auth.mode=v }
+        if let Some(v)=&args.client_ca { self.client_auth.ca_path=v.clone() }
+        if let Some(v)=args.http2 { self.http2.enabled=v }
+        if let Some(v)=args.h2_first { self.http2.first=v }
+        if let Some(v)=args.http3 { self.http3.enabled=v }
+        if let Some(v)=args.strong_etag { self.strong_etag=v }
+        if let Some(v)=args.autoindex { self.autoindex=v }
+        if let Some(v)=args.site_files { self.site_files=v }
+        if let Some(v)=args.compress_min_size { self.compression.min_size=v }
+        if let Some(v)=args.drain_timeout { self.shutdown.drain_timeout=v }
+        if let Some(v)=args.keep_alive { self.http1.keep_alive=v }
+        if let Some(v)=args.header_preset { self.security_headers.preset=v }
+    }
+
+    /// checks everything that can be checked without binding sockets, returns all problems at once
+    pub fn validate(&self)->Vec<String>{
+        let mut problems=vec![];
+
+        if !Path::new(&self.serve_dir).is_dir() {
+            problems.push(format!("serve_dir {} is not a directory",self.serve_dir));
+        }
+        if self.listener.endpoints.is_empty() {
+            if let Err(e)=self.listener.address.to_socket_addrs() {
+                problems.push(format!("listener.address {} is invalid: {e}",self.listener.address));
+            }
+        }
+        for endpoint in &self.listener.endpoints{
+            let kinds=[endpoint.address.is_some(),endpoint.unix.is_some(),endpoint.systemd].iter().filter(|k|**k).count();
+            if kinds!=1 { problems.push(format!("listener endpoint {} needs exactly one of address, unix or systemd",endpoint.name())) }
+            if let Some(address)=&endpoint.address {
+                if let Err(e)=address.to_socket_addrs() { problems.push(format!("listener endpoint {address} is invalid: {e}")) }
+            }
+            if endpoint.unix.is_some() && cfg!(not(unix)) {
+                problems.push(format!("unix sockets arent available on this platform, {}",endpoint.name()));
+            }
+            if let Some(mode)=&endpoint.mode {
+                if endpoint.unix.is_none() { problems.push(format!("mode {mode} only applies to unix sockets")) }
+                if u32::from_str_radix(mode, 8).map_or(true,|m|m>0o777) { problems.push(format!("mode {mode} isnt octal permissions like 660")) }
+            }
+            if endpoint.tls==Some(true) && !self.tls.enabled {
+                problems.push(format!("listener endpoint {} wants tls but tls.enabled is off",endpoint.name()));
+            }
+        }
+        if self.listener.endpoints.iter().filter(|e|e.systemd).count()>1 {
+            problems.push("only one listener endpoint can take the systemd sockets".to_owned());
+        }
+        if let Some(address)=&self.redirect.address {
+            if let Err(e)=address.to_socket_addrs() { problems.push(format!("redirect.address {address} is invalid: {e}")) }
+            if !self.tls.enabled { problems.push("redirect.address needs tls.enabled, there is no https to redirect to".to_owned()) }
+        }
+        if ![301,302,307,308].contains(&self.redirect.status) {
+            problems.push(format!("redirect.status {} is not 301, 302, 307 or 308",self.redirect.status));
+        }
+        // a missing development pair is generated, acme brings its own.
+        // without the default pair the server falls back to plain http like it always did
+        let default_pair=TlsConfig::default();
+        let default_pair=self.tls.key_path==default_pair.key_path && self.tls.cert_path==default_pair.cert_path;
+        if self.tls.enabled && !self.tls.dev && !self.acme.enabled && !default_pair {
+            let mut files=vec![("tls.key_path",&self.tls.key_path)];
+            if !is_pkcs12(&self.tls.key_path) { files.push(("tls.cert_path",&self.tls.cert_path)) }
+            for (name,path) in files{
+                if !Path::new(path).is_file() { problems.push(format!("{name} {path} does not exist")) }
+            }
+        }
+        if self.tls.dev && !self.tls.enabled {
+            problems.push("tls.dev needs tls.enabled".to_owned());
+        }
+        if self.tls.dev && self.tls.dev_persist && is_pkcs12(&self.tls.key_path) {
+            problems.push(format!("tls.dev_persist writes pem files, {} is a pkcs12 path",self.tls.key_path));
+        }
+        if self.acme.enabled {
+            if !self.tls.enabled { problems.push("acme.enabled needs tls.enabled".to_owned()) }
+            if self.tls.dev { problems.push("acme.enabled and tls.dev both want to provide the certificate".to_owned()) }
+            if self.acme.domains.is_empty() { problems.push("acme.domains is empty".to_owned()) }
+            if self.acme.domains.iter().any(|d|d.contains('*')) {
+                problems.push("acme.domains cant hold wildcards, they need dns-01 validation".to_owned());
+            }
+            if !self.acme.directory_url.starts_with("https://") {
+                problems.push(format!("acme.directory_url {} has to be https",self.acme.directory_url));
+            }
+            if let Some(path)=&self.acme.ca_path {
+                if !Path::new(path).is_file() { problems.push(format!("acme.ca_path {path} does not exist")) }
+            }
+        }
+        if self.client_auth.mode!=ClientAuthMode::None {
+            if !self.tls.enabled { problems.push("client_auth.mode needs tls.enabled".to_owned()) }
+            if !Path::new(&self.client_auth.ca_path).is_file() {
+                problems.push(format!("client_auth.ca_path {} does not exist",self.client_auth.ca_path));
+            }
+        }
+        for rule in &self.client_auth.rules{
+            if !rule.path.starts_with('/') { problems.push(format!("client_auth rule path {} has to start with /",rule.path)) }
+            if self.client_auth.mode==ClientAuthMode::None {
+                problems.push(format!("client_auth rule for {} can never pass without client_auth.mode",rule.path));
+            }
+        }
+        if self.http3.enabled && !self.tls.enabled {
+            problems.push("http3.enabled needs tls.enabled, quic is always encrypted".to_owned());
+        }
+        if self.http1.max_requests==0 {
+            problems.push("http1.max_requests must be at least 1".to_owned());
+        }
+        if let Some(size)=self.http2.max_frame_size {
+            if !(16384..=16777215).contains(&size) {
+                problems.push(format!("http2.max_frame_size {size} must be between 16384 and 16777215"));
+            }
+        }
+        if let Some(size)=self.http2.initial_window_size {
+            if size>2147483647 { problems.push(format!("http2.initial_window_size {size} exceeds 2^31-1")) }
+        }
+        if let Some(size)=self.http2.connection_window_size {
+            if !(65535..=2147483647).contains(&size) {
+                problems.push(format!("http2.connection_window_size {size} must be between 65535 and 2^31-1"));
+            }
+        }
+        if self.http2.max_concurrent_streams==Some(0) {
+            problems.push("http2.max_concurrent_streams 0 would refuse every request".to_owned());
+        }
+        for pattern in self.compression.skip.iter().chain(&self.compression.allow){
+            if pattern!="*" && !pattern.contains('/') { problems.push(format!("compression pattern {pattern} is not a mime type")) }
+        }
+        let mut named: Vec<(&str,&str)>=self.headers.iter().map(|(k,v)|(k.as_str(),v.as_str())).collect();
+        for rule in &self.header_rules{
+            named.extend(rule.set.iter().map(|(k,v)|(k.as_str(),v.as_str())));
+            named.extend(rule.remove.iter().map(|k|(k.as_str(),"")));
+        }
+        named.push(("Content-Security-Policy",&self.security_headers.content_security_policy));
+        named.push(("Referrer-Policy",&self.security_headers.referrer_policy));
+        for (name,value) in named{
+            if name.is_empty() || !name.bytes().all(|b|b.is_ascii_alphanumeric()||b"!#$%&'*+-.^_`|~".contains(&b)) {
+                problems.push(format!("header name {name:?} is invalid"));
+            }
+            if value.bytes().any(|b|b==b'\r'||b==b'\n'||b==0) { problems.push(format!("header value of {name} contains a line break")) }
+        }
+        for rule in &self.header_rules{
+            if let Some(path)=&rule.path {
+                if !path.starts_with('/') && !path.starts_with('*') { problems.push(format!("header rule path {path} has to start with / or *")) }
+            }
+            for pattern in &rule.mime{
+                if pattern!="*" && !pattern.contains('/') { problems.push(format!("header rule mime {pattern} is not a mime type")) }
+            }
+            if rule.set.is_empty() && rule.remove.is_empty() {
+                problems.push(format!("header rule for {} neither sets nor removes anything",rule.path.as_deref().unwrap_or("every path")));
+            }
+        }
+        for vhost in &self.vhosts{
+            if vhost.hosts.is_empty() { problems.push(format!("vhost for {} has no hosts",vhost.serve_dir)) }
+            for host in &vhost.hosts{
+                if host.is_empty() || host.trim_start_matches("*.").contains('*') {
+                    problems.push(format!("vhost host {host:?} is invalid, wildcards are only allowed as a leading *."));
+                }
+            }
+            if !Path::new(&vhost.serve_dir).is_dir() {
+                problems.push(format!("vhost serve_dir {} is not a directory",vhost.serve_dir));
+            }
+            match (&vhost.key_path,&vhost.cert_path){
+                (Some(key),Some(cert))=>if self.tls.enabled {
+                    for path in [key,cert]{
+                        if !Path::new(path).is_file() { problems.push(format!("vhost certificate file {path} does not exist")) }
+                    }
+                },
+                (Some(bundle),None) if is_pkcs12(bundle)=>if self.tls.enabled && !Path::new(bundle).is_file() {
+                    problems.push(format!("vhost certificate file {bundle} does not exist"));
+                },
+                (None,None)=>(),
+                _=>problems.push(format!("vhost {} needs both key_path and cert_path",vhost.hosts.join(", "))),
+            }
+        }
+        for name in &self.middleware.enabled{
+            if !middleware::NAMES.contains(&name.as_str()) {
+                problems.push(format!("unknown middleware {name}"));
+            }
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn args(list: &[&str])->std::io::Result<Args>{
+        let mut all=vec!["static_serve".to_owned()];
+        all.extend(list.iter().map(|a|a.to_string()));
+        Args::parse(&all)
+    }
+
+    #[test]
+    fn positional_arguments(){
+        let parsed=args(&["0.0.0.0:80","www","site.key","site.crt"]).unwrap();
+        assert_eq!(parsed.address.as_deref(), Some("0.0.0.0:80"));
+        assert_eq!(parsed.serve_dir.as_deref(), Some("www"));
+        assert_eq!(parsed.key_path.as_deref(), Some("site.key"));
+        assert_eq!(parsed.cert_path.as_deref(), Some("site.crt"));
+
+        let parsed=args(&["0.0.0.0:80"]).unwrap();
+        assert_eq!((parsed.address.as_deref(),parsed.serve_dir),(Some("0.0.0.0:80"),None));
+
+        // a flag wins over the positional in the same place
+        let parsed=args(&["--serve-dir","flagged","0.0.0.0:80","www"]).unwrap();
+        assert_eq!(parsed.serve_dir.as_deref(), Some("flagged"));
+        assert_eq!(parsed.address.as_deref(), Some("0.0.0.0:80"));
+
+        assert!(args(&["a","b","c","d","e"]).is_err());
+    }
+
+    #[test]
+    fn flags(){
+        let parsed=args(&[
+            "--address=127.0.0.1:1","-d","www","--compress-min-size=10","--header-preset","secure",
+            "--client-auth=required","--drain-timeout","3","--autoindex","--http2=off","-l","unix:/run/s.sock","--listen=systemd",
+        ]).unwrap();
+        assert_eq!(parsed.address.as_deref(), Some("127.0.0.1:1"));
+        assert_eq!(parsed.serve_dir.as_deref(), Some("www"));
+        assert_eq!(parsed.compress_min_size, Some(10));
+        assert_eq!(parsed.header_preset, Some(HeaderPreset::Secure));
+        assert_eq!(parsed.client_auth, Some(ClientAuthMode::Required));
+        assert_eq!(parsed.drain_timeout, Some(3));
+        assert_eq!(parsed.autoindex, Some(true));
+        assert_eq!(parsed.http2, Some(false));
+        assert_eq!(parsed.listen.iter().map(|l|l.name()).collect::<Vec<_>>(), ["unix:/run/s.sock","systemd"]);
+
+        let parsed=args(&["--no-tls","--no-http2","--no-http3","--no-keep-alive=no","--site-files=yes"]).unwrap();
+        assert_eq!((parsed.tls,parsed.http2,parsed.http3,parsed.keep_alive,parsed.site_files), (Some(false),Some(false),Some(false),Some(true),Some(true)));
+        assert_eq!(args(&["--tls=0"]).unwrap().tls, Some(false));
+
+        for bad in [&["--bogus"][..],&["--address"],&["--tls=maybe"],&["--compress-min-size","big"],&["--header-preset=strict"],&["--client-auth","sometimes"]]{
+            assert!(args(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn unknown_fields_are_refused(){
+        for text in ["bogus = 1","[tls]\nkey = \"x\"","[[header_rules]]\npath = \"/x\"\nunknown = 1","[[vhosts]]\nhosts = [\"a\"]\nserve_dir = \".\"\nsni = true"]{
+            assert!(toml::from_str::<Config>(text).is_err(), "{text}");
+        }
+        let config: Config=toml::from_str("autoindex = true\n[tls]\nenabled = false").unwrap();
+        assert!(config.autoindex && !config.tls.enabled);
+        // untouched tables keep their defaults
+        assert_eq!(config.shutdown.drain_timeout, 30);
+
+        let path=std::env::temp_dir().join(format!("static-serve-unknown-{}.toml",std::process::id()));
+        fs::write(&path, "[listener]\nport = 80").unwrap();
+        let err=Config::from_file(&path.to_string_lossy()).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("port"), "{err}");
+        assert!(err.to_string().contains(&*path.to_string_lossy()), "{err}");
+    }
+
+    // the only test touching the environment, so nothing races on it
+    #[test]
+    fn flags_over_env_over_file_over_defaults(){
+        let path=std::env::temp_dir().join(format!("static-serve-precedence-{}.toml",std::process::id()));
+        fs::write(&path, "serve_dir = \"from-file\"\nautoindex = true\n[listener]\naddress = \"1.1.1.1:1\"\n[compression]\nmin_size = 5").unwrap();
+        let path=path.to_string_lossy().to_string();
+        env::set_var("SERVE_DIR", "from-env");
+        env::set_var("ADDRESS", "2.2.2.2:2");
+        env::set_var("COMPRESS_MIN_SIZE", "7");
+
+        let config=Config::load(&args(&["--config",&path,"--serve-dir","from-flag"]).unwrap()).unwrap();
+        assert_eq!(config.serve_dir, "from-flag");
+        assert_eq!(config.listener.address, "2.2.2.2:2");
+        assert_eq!(config.compression.min_size, 7);
+        assert!(config.autoindex);
+        assert_eq!(config.shutdown.drain_timeout, 30);
+
+        env::remove_var("ADDRESS");
+        env::remove_var("COMPRESS_MIN_SIZE");
+        env::set_var("CONFIG", &path);
+        let config=Config::load(&args(&[]).unwrap()).unwrap();
+        assert_eq!((config.serve_dir.as_str(),config.listener.address.as_str(),config.compression.min_size), ("from-env","1.1.1.1:1",5));
+
+        env::remove_var("SERVE_DIR");
+        env::remove_var("CONFIG");
+        fs::remove_file(&path).unwrap();
+    }
+
+    // the tests run from the package root
+    const FILE: &str="Cargo.toml";
+    const DIR: &str="src";
+
+    fn valid()->Config{
+        Config{ serve_dir: DIR.to_owned(), ..Config::default() }
+    }
+
+    fn endpoint(spec: &str)->ListenEndpoint{
+        ListenEndpoint::parse(spec)
+    }
+
+    fn vhost(hosts: &[&str], serve_dir: &str, key: Option<&str>, cert: Option<&str>)->VhostConfig{
+        VhostConfig{
+            hosts: hosts.iter().map(|h|h.to_string()).collect(), serve_dir: serve_dir.to_owned(),
+            key_path: key.map(str::to_owned), cert_path: cert.map(str::to_owned), pkcs12_password: None,
+        }
+    }
+
+    fn header_rule(path: &str, mime: &[&str], set: bool)->HeaderRule{
+        HeaderRule{
+            path: Some(path.to_owned()), mime: mime.iter().map(|m|m.to_string()).collect(), remove: vec![],
+            set: if set { [("X-A".to_owned(),"b".to_owned())].into() } else { BTreeMap::new() },
+        }
+    }
+
+    fn client_rule(path: &str)->ClientRule{
+        ClientRule{ path: path.to_owned(), subjects: vec![], sans: vec![] }
+    }
+
+    // a change to a valid config and the problems it should cause, in order
+    type Case=(fn(&mut Config),&'static [&'static str]);
+
+    #[test]
+    fn validation(){
+        assert_eq!(valid().validate(), Vec::<String>::new());
+
+        let cases: Vec<Case>=vec![
+            (|c|c.serve_dir="/does/not/exist".to_owned(), &["serve_dir /does/not/exist is not a directory"]),
+            (|c|c.listener.address="nope".to_owned(), &["listener.address nope is invalid"]),
+            (|c|c.listener.endpoints=vec![ListenEndpoint{ unix: Some("/s".to_owned()), ..endpoint("127.0.0.1:1") }], &["needs exactly one of address, unix or systemd"]),
+            (|c|c.listener.endpoints=vec![endpoint("bad")], &["listener endpoint bad is invalid"]),
+            (|c|c.listener.endpoints=vec![ListenEndpoint{ mode: Some("660".to_owned()), ..endpoint("127.0.0.1:1") }], &["mode 660 only applies to unix sockets"]),
+            (|c|c.listener.endpoints=vec![ListenEndpoint{ mode: Some("999".to_owned()), ..endpoint("unix:/s") }], &["mode 999 isnt octal permissions"]),
+            (|c|c.listener.endpoints=vec![ListenEndpoint{ mode: Some("1777".to_owned()), ..endpoint("unix:/s") }], &["mode 1777 isnt octal permissions"]),
+            (|c|{ c.tls.enabled=false; c.listener.endpoints=vec![ListenEndpoint{ tls: Some(true), ..endpoint("127.0.0.1:1") }] }, &["wants tls but tls.enabled is off"]),
+            (|c|c.listener.endpoints=vec![endpoint("systemd"),endpoint("systemd")], &["only one listener endpoint can take the systemd sockets"]),
+            (|c|c.redirect.address=Some("bad".to_owned()), &["redirect.address bad is invalid"]),
+            (|c|{ c.tls.enabled=false; c.redirect.address=Some("127.0.0.1:80".to_owned()) }, &["redirect.address needs tls.enabled"]),
+            (|c|c.redirect.status=303, &["redirect.status 303 is not 301, 302, 307 or 308"]),
+            (|c|{ c.tls.key_path="/missing.key".to_owned(); c.tls.cert_path="/missing.crt".to_owned() }, &["tls.key_path /missing.key does not exist","tls.cert_path /missing.crt does not exist"]),
+            // a bundle holds the certificate as well
+            (|c|c.tls.key_path="/missing.p12".to_owned(), &["tls.key_path /missing.p12 does not exist"]),
+            (|c|{ c.tls.enabled=false; c.tls.dev=true }, &["tls.dev needs tls.enabled"]),
+            (|c|{ c.tls.dev=true; c.tls.dev_persist=true; c.tls.key_path="dev.p12".to_owned() }, &["tls.dev_persist writes pem files"]),
+            (|c|{ c.acme.enabled=true; c.acme.domains=vec!["a.test".to_owned()]; c.tls.enabled=false }, &["acme.enabled needs tls.enabled"]),
+            (|c|{ c.acme.enabled=true; c.acme.domains=vec!["a.test".to_owned()]; c.tls.dev=true }, &["acme.enabled and tls.dev both want to provide the certificate"]),
+            (|c|c.acme.enabled=true, &["acme.domains is empty"]),
+            (|c|{ c.acme.enabled=true; c.acme.domains=vec!["*.a.test".to_owned()] }, &["acme.domains cant hold wildcards"]),
+            (|c|{ c.acme.enabled=true; c.acme.domains=vec!["a.test".to_owned()]; c.acme.directory_url="http://ca".to_owned() }, &["acme.directory_url http://ca has to be https"]),
+            (|c|{ c.acme.enabled=true; c.acme.domains=vec!["a.test".to_owned()]; c.acme.ca_path=Some("/missing.pem".to_owned()) }, &["acme.ca_path /missing.pem does not exist"]),
+            (|c|{ c.client_auth.mode=ClientAuthMode::Optional; c.client_auth.ca_path=FILE.to_owned(); c.tls.enabled=false }, &["client_auth.mode needs tls.enabled"]),
+            (|c|c.client_auth.mode=ClientAuthMode::Required, &["client_auth.ca_path client-ca.crt does not exist"]),
+            (|c|{ c.client_auth.mode=ClientAuthMode::Optional; c.client_auth.ca_path=FILE.to_owned(); c.client_auth.rules=vec![client_rule("internal")] }, &["client_auth rule path internal has to start with /"]),
+            (|c|c.client_auth.rules=vec![client_rule("/internal")], &["client_auth rule for /internal can never pass without client_auth.mode"]),
+            (|c|{ c.http3.enabled=true; c.tls.enabled=false }, &["http3.enabled needs tls.enabled"]),
+            (|c|c.http1.max_requests=0, &["http1.max_requests must be at least 1"]),
+            (|c|c.http2.max_frame_size=Some(1024), &["http2.max_frame_size 1024 must be between"]),
+            (|c|c.http2.initial_window_size=Some(1<<31), &["http2.initial_window_size 2147483648 exceeds 2^31-1"]),
+            (|c|c.http2.connection_window_size=Some(100), &["http2.connection_window_size 100 must be between"]),
+            (|c|c.http2.max_concurrent_streams=Some(0), &["http2.max_concurrent_streams 0 would refuse every request"]),
+            (|c|c.compression.skip=vec!["html".to_owned()], &["compression pattern html is not a mime type"]),
+            (|c|{ c.headers.insert("Bad Name".to_owned(), "x".to_owned()); }, &["header name \"Bad Name\" is invalid"]),
+            (|c|{ c.headers.insert("X-A".to_owned(), "a\r\nb".to_owned()); }, &["header value of X-A contains a line break"]),
+            (|c|c.security_headers.referrer_policy="a\nb".to_owned(), &["header value of Referrer-Policy contains a line break"]),
+            (|c|c.header_rules=vec![header_rule("assets/**", &[], true)], &["header rule path assets/** has to start with / or *"]),
+            (|c|c.header_rules=vec![header_rule("/**", &["html"], true)], &["header rule mime html is not a mime type"]),
+            (|c|c.header_rules=vec![header_rule("/x", &[], false)], &["header rule for /x neither sets nor removes anything"]),
+            (|c|c.vhosts=vec![vhost(&[], DIR, None, None)], &["vhost for src has no hosts"]),
+            (|c|c.vhosts=vec![vhost(&["a.*.test"], DIR, None, None)], &["vhost host \"a.*.test\" is invalid"]),
+            (|c|c.vhosts=vec![vhost(&["a.test"], "/does/not/exist", None, None)], &["vhost serve_dir /does/not/exist is not a directory"]),
+            (|c|c.vhosts=vec![vhost(&["a.test"], DIR, Some("/a.key"), Some("/a.crt"))], &["vhost certificate file /a.key does not exist","vhost certificate file /a.crt does not exist"]),
+            (|c|c.vhosts=vec![vhost(&["a.test"], DIR, Some("/a.pfx"), None)], &["vhost certificate file /a.pfx does not exist"]),
+            (|c|c.vhosts=vec![vhost(&["a.test"], DIR, Some("a.key"), None)], &["vhost a.test needs both key_path and cert_path"]),
+            (|c|c.middleware.enabled=vec!["nope".to_owned()], &["unknown middleware nope"]),
+        ];
+        for (change,expected) in cases{
+            let mut config=valid();
+            change(&mut config);
+            let problems=config.validate();
+            assert_eq!(problems.len(), expected.len(), "{problems:?}");
+            for (problem,expected) in problems.iter().zip(expected){
+                assert!(problem.contains(expected), "{problem} should contain {expected}");
+            }
+        }
+
+        // without tls the vhost pairs arent looked at
+        let mut config=valid();
+        config.tls.enabled=false;
+        config.vhosts=vec![vhost(&["a.test"], DIR, Some("/a.key"), Some("/a.crt"))];
+        assert!(config.validate().is_empty());
+    }
+
+    #[cfg(not(unix))]
+    #[test]
+    fn unix_sockets_need_unix(){
+        let mut config=valid();
+        config.listener.endpoints=vec![endpoint("unix:/s")];
+        assert!(config.validate().iter().any(|p|p.contains("unix sockets arent available")));
+    }
+}
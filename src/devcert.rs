@@ -0,0 +1,120 @@
+use std::{fs, io::{Error, ErrorKind}, path::Path};
+
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+use rustls::{Certificate, PrivateKey};
+
+use crate::{config::{Config, TlsConfig}, tls};
+
+/// always part of a development certificate
+pub const LOCAL_NAMES: [&str; 3]=["localhost","127.0.0.1","::1"];
+
+/// pem encoded key and self signed certificate for `names`, ip addresses become ip sans
+pub fn generate(names: &[String])->std::io::Result<(String,String)>{
+    let invalid=|e: rcgen::Error|Error::new(ErrorKind::InvalidInput, format!("couldnt generate certificate {e}"));
+    let mut params=CertificateParams::new(names.to_vec()).map_err(invalid)?;
+    let mut dn=DistinguishedName::new();
+    dn.push(DnType::CommonName, "static-serve development certificate");
+    params.distinguished_name=dn;
+    let key=KeyPair::generate().map_err(invalid)?;
+    let cert=params.self_signed(&key).map_err(invalid)?;
+    Ok((key.serialize_pem(),cert.pem()))
+}
+
+/// the local names plus `tls.dev_names` and every vhost host, without duplicates
+pub fn dev_names(config: &Config)->Vec<String>{
+    let mut names: Vec<String>=LOCAL_NAMES.iter().map(|n|n.to_string()).collect();
+    let extra=config.tls.dev_names.iter().chain(config.vhosts.iter().flat_map(|v|&v.hosts));
+    for name in extra{
+        if !names.contains(name) { names.push(name.clone()) }
+    }
+    names
+}
+
+/// writes a file only the owner can read, for private keys
+pub fn write_private(path: &str, content: &str)->std::io::Result<()>{
+    #[cfg(unix)]
+    {
+        use std::{io::Write, os::unix::fs::OpenOptionsExt};
+        fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?.write_all(content.as_bytes())
+    }
+    #[cfg(not(unix))]
+    fs::write(path, content)
+}
+
+pub fn write_pair(key_path: &str, cert_path: &str, key: &str, cert: &str)->std::io::Result<()>{
+    write_private(key_path, key)?;
+    fs::write(cert_path, cert)
+}
+
+pub fn parse_pair(key: &str, cert: &str)->std::io::Result<(PrivateKey,Vec<Certificate>)>{
+    let key=rustls_pemfile::pkcs8_private_keys(&mut key.as_bytes())?.into_iter().next()
+        .ok_or_else(||Error::new(ErrorKind::InvalidData, "generated key missing"))?;
+    let certs=rustls_pemfile::certs(&mut cert.as_bytes())?;
+    Ok((PrivateKey(key),certs.into_iter().map(Certificate).collect()))
+}
+
+/// the certificate for `--dev-tls`. with `tls.dev_persist` an existing key_path/cert_path pair is
+/// reused and a fresh one is written there, otherwise it only lives in memory
+pub fn dev_pair(config: &Config)->Option<(PrivateKey,Vec<Certificate>)>{
+    let TlsConfig{ key_path, cert_path, dev_persist, .. }=&config.tls;
+    if *dev_persist && Path::new(key_path).is_file() && Path::new(cert_path).is_file() {
+        println!("\x1b[33mreusing development certificate {cert_path}\x1b[0m");
+        return tls::load_key_cert(key_path, cert_path, "");
+    }
+
+    let names=dev_names(config);
+    let (key,cert)=match generate(&names){
+        Ok(pair)=>pair,
+        Err(e)=>{
+            eprintln!("{e}");
+            return None;
+        }
+    };
+    if *dev_persist {
+        match write_pair(key_path, cert_path, &key, &cert){
+            Ok(())=>println!("\x1b[33mwrote development certificate to {cert_path} and {key_path}\x1b[0m"),
+            Err(e)=>eprintln!("couldnt save development certificate {e:?}"),
+        }
+    }
+    println!("\x1b[33mgenerated self signed certificate for {}\x1b[0m",names.join(", "));
+    match parse_pair(&key, &cert){
+        Ok(pair)=>Some(pair),
+        Err(e)=>{
+            eprintln!("reading generated certificate failed {e:?}");
+            None
+        }
+    }
+}
+
+/// `gen-cert [--key PATH] [--cert PATH] [--force] [NAME...]`, writes a self signed pair and exits
+pub fn gen_cert(args: &[String])->std::io::Result<()>{
+    let defaults=TlsConfig::default();
+    let (mut key_path,mut cert_path,mut force)=(defaults.key_path,defaults.cert_path,false);
+    let mut names: Vec<String>=vec![];
+    let mut iter=args.iter();
+    while let Some(arg)=iter.next(){
+        let (flag,inline)=match arg.split_once('='){
+            Some((f,v))=>(f,Some(v.to_owned())),
+            None=>(arg.as_str(),None),
+        };
+        let mut value=||inline.clone().or_else(||iter.next().cloned()).ok_or_else(||Error::new(ErrorKind::InvalidInput, format!("{flag} needs a value")));
+        match flag{
+            "--key"=>key_path=value()?,
+            "--cert"=>cert_path=value()?,
+            "--force"=>force=true,
+            f if f.starts_with('-')=>return Err(Error::new(ErrorKind::InvalidInput, format!("unknown gen-cert flag {f}"))),
+            _=>names.push(arg.clone()),
+        }
+    }
+    if names.is_empty() { names=LOCAL_NAMES.iter().map(|n|n.to_string()).collect() }
+
+    for path in [&key_path,&cert_path]{
+        if !force && Path::new(path).exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{path} exists, pass --force to overwrite it")));
+        }
+    }
+    let (key,cert)=generate(&names)?;
+    write_pair(&key_path, &cert_path, &key, &cert)?;
+    println!("\x1b[32mwrote {key_path} and {cert_path}\x1b[0m for {}",names.join(", "));
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use async_compression::{
+    tokio::bufread::{BrotliEncoder, GzipEncoder, ZlibEncoder, ZstdEncoder}, Level
+};
+use tokio::io::{AsyncRead, BufReader};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding{
+    Identity,
+    Brotli,
+    Zstd,
+    Gzip,
+    Deflate,
+}
+
+// server preference, breaks ties between codings the client weighs equally
+pub const PREFERENCE: [Encoding; 4]=[Encoding::Brotli, Encoding::Zstd, Encoding::Gzip, Encoding::Deflate];
+
+// identity is acceptable when not mentioned, but ranks below anything the client asked for
+const IMPLICIT_IDENTITY_Q: f32=0.001;
+
+impl Encoding{
+    pub fn name(&self)->&'static str{
+        match self{
+            Encoding::Identity=>"identity",
+            Encoding::Brotli=>"br",
+            Encoding::Zstd=>"zstd",
+            Encoding::Gzip=>"gzip",
+            Encoding::Deflate=>"deflate",
+        }
+    }
+
+    /// extension of a precompressed sibling file, if this coding has one
+    pub fn extension(&self)->Option<&'static str>{
+        match self{
+            Encoding::Brotli=>Some("br"),
+            Encoding::Zstd=>Some("zst"),
+            Encoding::Gzip=>Some("gz"),
+            _=>None,
+        }
+    }
+
+    fn matches(&self, name: &str)->bool{
+        name==self.name() || (*self==Encoding::Gzip && name=="x-gzip")
+    }
+}
+
+/// parses `Accept-Encoding` (or `Accept`, it has the same shape) into lowercased names and their q-values
+pub fn parse(header: &str)->Vec<(String,f32)>{
+    header.split(',').filter_map(|coding|{
+        let mut parts=coding.split(';');
+        let name=parts.next()?.trim().to_lowercase();
+        if name.is_empty() { return None }
+        let q=parts
+            .filter_map(|p|p.trim().strip_prefix("q=").or_else(||p.trim().strip_prefix("Q=")).map(|q|q.trim().to_owned()))
+            .next()
+            .map_or(1.0,|q|q.parse::<f32>().unwrap_or(0.0).clamp(0.0,1.0));
+        Some((name,q))
+    }).collect()
+}
+
+fn quality(accepted: &[(String,f32)], coding: Encoding)->f32{
+    if let Some((_,q))=accepted.iter().find(|(n,_)|coding.matches(n)){
+        return *q;
+    }
+    if let Some((_,q))=accepted.iter().find(|(n,_)|n=="*"){
+        return *q;
+    }
+    if coding==Encoding::Identity { IMPLICIT_IDENTITY_Q } else { 0.0 }
+}
+
+/// picks the best of `available` for the given `Accept-Encoding` values.
+/// earlier entries of `available` win ties, identity is the fallback when nothing is acceptable
+pub fn negotiate(header: Option<&Vec<String>>, available: &[Encoding])->Encoding{
+    let accepted=match header{
+        Some(h)=>parse(&h.join(",")),
+        None=>return Encoding::Identity,
+    };
+
+    let mut best=(Encoding::Identity,quality(&accepted, Encoding::Identity));
+    for coding in available.iter().rev(){
+        let q=quality(&accepted, *coding);
+        if q>0.0 && q>=best.1 { best=(*coding,q) }
+    }
+    best.0
+}
+
+/// wraps a reader so it yields the encoded bytes
+pub fn encoder<'a, R: AsyncRead+Unpin+Send+'a>(coding: Encoding, reader: R)->Box<dyn AsyncRead+Unpin+Send+'a>{
+    let reader=BufReader::new(reader);
+    match coding{
+        Encoding::Identity=>Box::new(reader),
+        // the default brotli quality is far too slow to run per request
+        Encoding::Brotli=>Box::new(BrotliEncoder::with_quality(reader, Level::Precise(5))),
+        Encoding::Zstd=>Box::new(ZstdEncoder::new(reader)),
+        Encoding::Gzip=>Box::new(GzipEncoder::new(reader)),
+        // the http deflate coding is the zlib format, not a raw deflate stream
+        Encoding::Deflate=>Box::new(ZlibEncoder::new(reader)),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn pick(header: &str)->Encoding{
+        negotiate(Some(&vec![header.to_owned()]), &PREFERENCE)
+    }
+
+    #[test]
+    fn parses_q_values(){
+        assert_eq!(parse("gzip, BR;q=0.5 ,zstd;Q=0,deflate;q=2,x;q=abc"), [
+            ("gzip".to_owned(),1.0),("br".to_owned(),0.5),("zstd".to_owned(),0.0),("deflate".to_owned(),1.0),("x".to_owned(),0.0),
+        ]);
+        assert!(parse(" , ;q=1").is_empty());
+    }
+
+    #[test]
+    fn server_preference_breaks_ties(){
+        assert_eq!(pick("gzip, br, zstd"), Encoding::Brotli);
+        assert_eq!(pick("gzip, zstd"), Encoding::Zstd);
+        assert_eq!(pick("deflate, gzip"), Encoding::Gzip);
+        assert_eq!(pick("x-gzip"), Encoding::Gzip);
+    }
+
+    #[test]
+    fn client_weights_win(){
+        assert_eq!(pick("br;q=0.5, gzip"), Encoding::Gzip);
+        assert_eq!(pick("gzip;q=0.1, identity;q=0.5"), Encoding::Identity);
+    }
+
+    #[test]
+    fn q_zero_excludes(){
+        assert_eq!(pick("gzip;q=0"), Encoding::Identity);
+        assert_eq!(pick("br;q=0, gzip"), Encoding::Gzip);
+        assert_eq!(pick("*;q=0, deflate"), Encoding::Deflate);
+        assert_eq!(pick("*, br;q=0"), Encoding::Zstd);
+    }
+
+    #[test]
+    fn identity_fallback(){
+        assert_eq!(negotiate(None, &PREFERENCE), Encoding::Identity);
+        assert_eq!(pick(""), Encoding::Identity);
+        assert_eq!(pick("compress"), Encoding::Identity);
+        assert_eq!(negotiate(Some(&vec!["br".to_owned()]), &[Encoding::Gzip]), Encoding::Identity);
+        // identity is only a fallback, anything the client asked for ranks above it
+        assert_eq!(pick("gzip;q=0.01"), Encoding::Gzip);
+    }
+}
@@ -1,19 +1,62 @@
-use crate::{middleware::{self, MiddlewareData}, structs::SharedData /*Http1Socket*/};
+use crate::{autoindex, clientauth, conditional::{self, Precondition}, encoding::{self, Encoding}, headers::{Served, WithHeaders}, middleware::{self, MiddlewareData}, range::{self, RangeResult}, redirect, site, structs::SharedData /*Http1Socket*/};
 
 use tokio::{
-    fs::{self, File}, io::AsyncReadExt,
+    fs::{self, File}, io::{AsyncReadExt, AsyncSeekExt},
 };
 use std::{
     // io::Read, 
-    path::{Component, Path, PathBuf}, sync::Arc
+    fs::Metadata, io::SeekFrom, path::{Component, Path, PathBuf}, sync::Arc
 };
 
 use rust_http::common::{Compression, HttpClient, HttpResult, HttpSocket, /*Stream, HttpConstructor,*/};
 
-pub async fn handler<S:HttpSocket+Sized+Send+'static>(shared: Arc<SharedData>, middle_data: Arc<MiddlewareData<S::Stream>>, mut req: S) -> HttpResult<()> {
-    println!("Serving connection");
+// files are sent in pieces of this size so memory per connection stays bounded
+const CHUNK_SIZE: usize = 64*1024;
+
+// methods static paths respond to, middleware endpoints decide for themselves
+pub const STATIC_ALLOW: &str = "GET, HEAD, OPTIONS";
+
+async fn find_precompressed(client: &HttpClient, path: &str) -> Option<(String, Metadata, Encoding)> {
+    let mut available=vec![];
+    for coding in encoding::PREFERENCE {
+        let Some(ext)=coding.extension() else { continue };
+        let sidecar=format!("{}.{}", path, ext);
+        if let Ok(meta)=fs::metadata(&sidecar).await {
+            if meta.is_file() { available.push((coding, sidecar, meta)) }
+        }
+    }
+    if available.is_empty() { return None }
+
+    let codings: Vec<Encoding>=available.iter().map(|(c,_,_)|*c).collect();
+    let chosen=encoding::negotiate(client.headers.get("accept-encoding"), &codings);
+    available.into_iter().find(|(c,_,_)|*c==chosen).map(|(c,sidecar,meta)|(sidecar,meta,c))
+}
+
+/// the request path without query and fragment, `..` and `.` segments dropped
+pub fn clean_path(path: &str) -> String {
+    let full_path = path.replace("\\","/").replace("/..","/");
+    let full_path = full_path.split(|c| c == '?' || c == '#').next().unwrap_or("");
+
+    let mut cleaned = PathBuf::new();
+    for comp in Path::new(full_path).components() {
+        match comp {
+            Component::Normal(s) => cleaned.push(s),
+            Component::RootDir => cleaned.push("/"),
+            _ => {} // Skip CurDir, ParentDir, Prefix, etc.
+        }
+    }
 
-    let serve_dir=&shared.serve_dir;
+    let full_path = cleaned.to_string_lossy().into_owned();
+    full_path.replace("\\","/")
+}
+
+/// the body to send, nothing for HEAD
+pub fn body<'a>(head: bool, content: &'a [u8]) -> &'a [u8] {
+    if head { b"" } else { content }
+}
+
+pub async fn handler<S:HttpSocket+Sized+Send+'static>(shared: Arc<SharedData>, middle_data: Arc<MiddlewareData<S::Stream>>, req: &mut S) -> HttpResult<()> {
+    println!("Serving connection");
 
     let client=match req.get_client().await{
         Err(err)=>{
@@ -22,79 +65,123 @@ pub async fn handler<S:HttpSocket+Sized+Send+'static>(shared: Arc<SharedData>, m
         },
         Ok(c)=>c.clone(),
     };
+    let Some(serve_dir)=shared.serve_dir_for(&client, middle_data.server_name.as_deref()) else {
+        let host=client.headers.get("host").or(client.headers.get(":authority")).and_then(|h|h.first()).cloned().unwrap_or_default();
+        let sni=middle_data.server_name.as_deref().unwrap_or_default();
+        return error_handler(&shared, Served{ serve_dir: &shared.serve_dir, tls: middle_data.tls }, 421, std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{host} requested over a tls session for {sni}")), req).await;
+    };
 
-    // dbg!(&client);
+    let served=Served{ serve_dir, tls: middle_data.tls };
 
-    if let Some(ae)=client.headers.get("accept-encoding"){
-        let s=ae.join(" ");
-        if s.contains("gzip"){ 
-            req.set_compression(Compression::Gzip).unwrap();  
-        }
+    if let Some(key_authorization)=shared.acme.http01(&client.path){
+        println!("answering acme http-01 challenge");
+        req.set_status(200, "OK".to_owned())?;
+        let _=req.set_header("Content-Type", "application/octet-stream");
+        return req.close(key_authorization.as_bytes()).await;
     }
 
-    let full_path: String = { 
-        let full_path: String = client.path.clone();
-        let full_path = full_path.replace("\\","/").replace("/..","/");
-        let full_path = full_path.split(|c| c == '?' || c == '#').next().unwrap_or("");
-        
-        let mut cleaned = PathBuf::new();
-        for comp in Path::new(full_path).components() {
-            match comp {
-                Component::Normal(s) => cleaned.push(s),
-                Component::RootDir => cleaned.push("/"),
-                _ => {} // Skip CurDir, ParentDir, Prefix, etc.
-            }
-        }
+    // dbg!(&client);
 
-        let full_path = cleaned.to_string_lossy().into_owned();
-        let full_path = full_path.replace("\\","/");
+    let mut url_path = clean_path(&client.path);
+    let mut full_path = serve_dir.to_owned() + &url_path;
+    println!("Full path: {}", &full_path);
 
+    if !clientauth::allowed(&shared.client_rules, middle_data.peer.as_deref(), &url_path){
+        let who=middle_data.peer.as_ref().map_or("no client certificate".to_owned(),|p|p.subject.clone());
+        return error_handler(&shared, served, 403, std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{who} may not access {url_path}")), req).await;
+    }
 
-        serve_dir.to_owned() + &full_path.to_string()
-    };
-    println!("Full path: {}", &full_path);
+    if let Some(site)=&shared.site_files {
+        if site::is_site_file(&url_path) {
+            return error_handler(&shared, served, 404, std::io::Error::new(std::io::ErrorKind::NotFound, format!("{url_path} isnt served")), req).await;
+        }
+        let query=client.path.split('#').next().unwrap_or_default().split_once('?').map_or("",|(_,q)|q);
+        let route=site.get(serve_dir).await.route(&url_path, query);
+        // without `!` a file at the path shadows the rule
+        let shadowed=match &route{
+            Some(r) if !r.force=>fs::metadata(&full_path).await.is_ok(),
+            _=>false,
+        };
+        if let Some(route)=route.filter(|_|!shadowed) {
+            if route.status!=200 { return redirect_handler(&shared, served, &client, route.status, &route.to, req).await }
+            println!("rewriting {} to {}", url_path, route.to);
+            url_path=clean_path(&route.to);
+            full_path=serve_dir.to_owned() + &url_path;
+            if site::is_site_file(&url_path) || !clientauth::allowed(&shared.client_rules, middle_data.peer.as_deref(), &url_path) {
+                return error_handler(&shared, served, 403, std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("rewrite to {url_path} refused")), req).await;
+            }
+        }
+    }
 
 
-    if let Some(n)=middleware::available(&client.path){
+    if let Some(n)=middleware::available(&client.path).filter(|n|shared.middleware.iter().any(|m|m==n)){
         println!("Middleware available: {}", n);
+        // the library can only compress middleware responses with gzip
+        if encoding::negotiate(client.headers.get("accept-encoding"), &[Encoding::Gzip])==Encoding::Gzip{
+            req.set_compression(Compression::Gzip).unwrap();  
+        }
         
         match middleware::call(n, &shared, &middle_data, &full_path, req).await{
             Ok(_)=>println!("middleware did not error"),
             Err(e)=>eprintln!("\x1b[31mmiddleware errored\x1b[0m {}",e),
         };
         Ok(())
+    } else if client.method=="OPTIONS" {
+        req.set_status(204, "No Content".to_owned())?;
+        let _=req.set_header("Allow", STATIC_ALLOW);
+        req.close(b"").await
+    } else if client.method!="GET" && client.method!="HEAD" {
+        error_handler(&shared, served, 405, std::io::Error::new(std::io::ErrorKind::Unsupported, format!("method {} not allowed", client.method)), req).await
     } else {
         let info_res = fs::metadata(&full_path).await;
         match info_res{
             Ok(info) => {
                 if info.is_file() {
-                    file_handler(&shared, &full_path,req).await
+                    file_handler(&shared, served, &client, &full_path, &info, req).await
                 } else if info.is_dir(){
-                    dir_handler(&shared, req, &full_path).await
+                    dir_handler(&shared, served, &client, req, &full_path).await
                 } else {
-                    error_handler(&shared, 409, std::io::Error::new(std::io::ErrorKind::Unsupported, "File is unusable"), req).await
+                    error_handler(&shared, served, 409, std::io::Error::new(std::io::ErrorKind::Unsupported, "File is unusable"), req).await
                 }
             },
             Err(err) => {
                 if err.kind() == std::io::ErrorKind::NotFound {
-                    error_handler(&shared,404, err, req).await
+                    error_handler(&shared, served, 404, err, req).await
                 } else {
-                    error_handler(&shared,500, err, req).await
+                    error_handler(&shared, served, 500, err, req).await
                 }
             },
         }
     }
 }
 
-pub async fn error_handler<S:HttpSocket>(_shared: &SharedData,code: u16, err: std::io::Error, mut req: S) -> HttpResult<()>{
+pub async fn error_handler<S:HttpSocket+Send>(shared: &SharedData, served: Served<'_>, code: u16, err: std::io::Error, req: &mut S) -> HttpResult<()>{
     eprintln!("Error of status {} occoured\n\x1b[31m{}\x1b[0m",code,err);
+    let client=req.get_client().await.cloned().unwrap_or_else(|_|HttpClient::empty());
+    let req=&mut WithHeaders::new(shared, &client, served, "text/plain", req);
+    let head=req.get_client().await.is_ok_and(|c|c.method=="HEAD");
     match code {
         404 => {
             println!("404 Not Found: {}", &req.get_client().await?.path);
             req.set_status(404, "Not found".to_owned())?;
             // req.status_msg="404 Not Found".to_owned();
             let _=req.set_header("Content-Type", "text/plain");
-            req.close(b"not found").await?;
+            req.close(body(head, b"not found")).await?;
+            Ok(())
+        },
+        403 => {
+            println!("403 Forbidden: {}", &req.get_client().await?.path);
+            req.set_status(403, "Forbidden".to_owned())?;
+            let _=req.set_header("Content-Type", "text/plain");
+            req.close(body(head, b"forbidden")).await?;
+            Ok(())
+        },
+        405 => {
+            println!("405 Method Not Allowed: {}", &req.get_client().await?.path);
+            req.set_status(405, "Method Not Allowed".to_owned())?;
+            let _=req.set_header("Allow", STATIC_ALLOW);
+            let _=req.set_header("Content-Type", "text/plain");
+            req.close(body(head, b"method not allowed")).await?;
             Ok(())
         },
         409 => {
@@ -103,7 +190,14 @@ pub async fn error_handler<S:HttpSocket>(_shared: &SharedData,code: u16, err: st
             // req.status=409;
             // req.status_msg="409 Conflict".to_owned();
             let _=req.set_header("Content-Type", "text/plain");
-            req.close(b"conflict").await?;
+            req.close(body(head, b"conflict")).await?;
+            Ok(())
+        },
+        421 => {
+            println!("421 Misdirected Request: {}", &req.get_client().await?.path);
+            req.set_status(421, "Misdirected Request".to_owned())?;
+            let _=req.set_header("Content-Type", "text/plain");
+            req.close(body(head, b"misdirected request")).await?;
             Ok(())
         },
         500 => {
@@ -112,7 +206,7 @@ pub async fn error_handler<S:HttpSocket>(_shared: &SharedData,code: u16, err: st
             // req.status=500;
             // req.status_msg="500 Internal Server Error".to_owned();
             let _=req.set_header("Content-Type", "text/plain");
-            req.close(b"internal server error").await?;
+            req.close(body(head, b"internal server error")).await?;
             Ok(())
         },
         _ => {
@@ -121,16 +215,25 @@ pub async fn error_handler<S:HttpSocket>(_shared: &SharedData,code: u16, err: st
             // req.status=code;
             // req.status_msg=format!("{}: {}", code, err);
             let _=req.set_header("Content-Type", "text/plain");
-            req.close(b"internal server error").await?;
+            req.close(body(head, b"internal server error")).await?;
             Ok(())
         }
         
     }
 }
 
-pub async fn file_handler<S:HttpSocket>(shared: &SharedData, path: &str, mut res: S) -> HttpResult<()> {
+/// answers with a redirect from `_redirects`
+pub async fn redirect_handler<S:HttpSocket+Send>(shared: &SharedData, served: Served<'_>, client: &HttpClient, status: u16, location: &str, res: &mut S) -> HttpResult<()> {
+    println!("{} redirect {} to {}", status, client.path, location);
+    let res=&mut WithHeaders::new(shared, client, served, "text/plain", res);
+    res.set_status(status, redirect::reason(status).to_owned())?;
+    let _=res.set_header("Location", location);
+    let _=res.set_header("Content-Type", "text/plain");
+    res.close(body(client.method=="HEAD", format!("moved to {location}\n").as_bytes())).await
+}
+
+pub async fn file_handler<S:HttpSocket+Send>(shared: &SharedData, served: Served<'_>, client: &HttpClient, path: &str, meta: &Metadata, res: &mut S) -> HttpResult<()> {
     let mime=&shared.mime;
-    let mut file = File::open(path).await.unwrap();
     // let mut buffer = vec![];
     let parts: Vec<&str>=path.split(".").collect::<Vec<&str>>();
     let last=parts[parts.len()-1];
@@ -149,22 +252,148 @@ pub async fn file_handler<S:HttpSocket>(shared: &SharedData, path: &str, mut res
         res.set_header("Content-Type", "image/jpeg");
     } else if path.ends_with(".gif") {
         res.set_header("Content-Type", "image/gif");
-    } else*/ if let Some(ct)=mime.get(last) {
-        let _=res.set_header("Content-Type", ct);
-    } else {
-        let _=res.set_header("Content-Type", "application/octet-stream");
+    }*/
+    let content_type=mime.get(last).copied().unwrap_or("application/octet-stream");
+    let head=client.method=="HEAD";
+    let res=&mut WithHeaders::new(shared, client, served, content_type, res);
+
+    // a precompressed sibling replaces the file as the representation, keeping the originals content type
+    let precompressed=find_precompressed(client, path).await;
+    let (path, meta, precoded)=match &precompressed{
+        Some((sidecar, sidecar_meta, coding))=>(sidecar.as_str(), sidecar_meta, Some(*coding)),
+        None=>(path, meta, None),
     };
+    let mut file = File::open(path).await?;
+    let size=meta.len();
+
+    // otherwise compress on the fly, unless the body is too small or already compressed.
+    // partial content has to be served from the identity encoding.
+    // HEAD negotiates like GET so both report the same encoding and etag
+    let compressible=shared.compressible.get(content_type).copied().unwrap_or(false);
+    let dynamic=if precoded.is_none() && compressible && size>=shared.compress_min_size && !client.headers.contains_key("range") {
+        encoding::negotiate(client.headers.get("accept-encoding"), &encoding::PREFERENCE)
+    } else { Encoding::Identity };
+
+    let _=res.set_header("Vary", "Accept-Encoding");
+    if let Some(coding)=precoded{
+        println!("serving precompressed {}", path);
+        let _=res.set_header("Content-Encoding", coding.name());
+    } else if dynamic!=Encoding::Identity{
+        let _=res.set_header("Content-Encoding", dynamic.name());
+    }
+
+    let modified=conditional::last_modified(meta);
+    let etag=conditional::entity_tag(shared.etag_cache.as_ref(), path, meta).await;
+    let etag=if dynamic==Encoding::Identity { etag } else { conditional::with_coding(&etag, dynamic.name()) };
+    let _=res.set_header("Accept-Ranges", "bytes");
+    let _=res.set_header("ETag", &etag);
+    if let Some(m)=modified{
+        let _=res.set_header("Last-Modified", &httpdate::fmt_http_date(m));
+    }
+
+    match conditional::evaluate(&client.method, &client.headers, &etag, modified){
+        Precondition::Proceed=>(),
+        Precondition::NotModified=>{
+            res.set_status(304, "Not Modified".to_owned())?;
+            return res.close(b"").await;
+        },
+        Precondition::Failed=>{
+            println!("412 Precondition Failed: {}", path);
+            res.set_status(412, "Precondition Failed".to_owned())?;
+            let _=res.set_header("Content-Type", "text/plain");
+            return res.close(body(head, b"precondition failed")).await;
+        },
+    };
+
+    let ranges=match client.headers.get("range"){
+        Some(r) if client.headers.get("if-range").is_none_or(|ir|range::if_range_matches(&ir[0], &etag, modified))=>{
+            range::parse_range(&r[0], size)
+        },
+        _=>RangeResult::Full,
+    };
+
+    let mut buffer = vec![0; CHUNK_SIZE];
+
+    match ranges{
+        RangeResult::Full if dynamic!=Encoding::Identity=>{
+            // the compressed length isnt known up front, the library falls back to chunked transfer then
+            let _=res.set_header("Content-Type", content_type);
+            if !head { stream_encoded(res, &mut file, dynamic, &mut buffer).await?; }
+        },
+        RangeResult::Full=>{
+            let _=res.set_header("Content-Type", content_type);
+            let _=res.set_header("Content-Length", &size.to_string());
+            if !head { stream_range(res, &mut file, 0, size, &mut buffer).await?; }
+        },
+        RangeResult::Unsatisfiable=>{
+            println!("416 Range Not Satisfiable: {}", path);
+            res.set_status(416, "Range Not Satisfiable".to_owned())?;
+            let _=res.set_header("Content-Range", &format!("bytes */{}",size));
+            let _=res.set_header("Content-Type", "text/plain");
+            return res.close(body(head, b"range not satisfiable")).await;
+        },
+        RangeResult::Partial(ranges) if ranges.len()==1=>{
+            let (start,end)=ranges[0];
+            res.set_status(206, "Partial Content".to_owned())?;
+            let _=res.set_header("Content-Type", content_type);
+            let _=res.set_header("Content-Range", &range::content_range(start, end, size));
+            let _=res.set_header("Content-Length", &(end-start+1).to_string());
+            if !head { stream_range(res, &mut file, start, end-start+1, &mut buffer).await?; }
+        },
+        RangeResult::Partial(ranges)=>{
+            let boundary=range::boundary();
+            let headers: Vec<String>=ranges.iter().map(|(start,end)|range::part_header(&boundary, content_type, *start, *end, size)).collect();
+            let closing=range::closing_boundary(&boundary);
+            let length=headers.iter().map(|h|h.len() as u64).sum::<u64>()
+                + ranges.iter().map(|(start,end)|end-start+1).sum::<u64>()
+                + closing.len() as u64;
 
-    let mut buffer = vec![];
-    file.read_to_end(&mut buffer).await.unwrap();
-    res.close(&buffer).await?;
+            res.set_status(206, "Partial Content".to_owned())?;
+            let _=res.set_header("Content-Type", &format!("multipart/byteranges; boundary={}",boundary));
+            let _=res.set_header("Content-Length", &length.to_string());
+            if head { return res.close(b"").await }
+            for ((start,end),header) in ranges.iter().zip(headers){
+                res.write(header.as_bytes()).await?;
+                stream_range(res, &mut file, *start, end-start+1, &mut buffer).await?;
+            }
+            res.write(closing.as_bytes()).await?;
+        },
+    };
+    res.close(b"").await?;
     
     Ok(())
 }
 
-pub async fn dir_handler<S:HttpSocket>(shared: &SharedData, res: S,path: &str) -> HttpResult<()> {
+async fn stream_encoded<S:HttpSocket>(res: &mut S, file: &mut File, coding: Encoding, buffer: &mut [u8]) -> HttpResult<()> {
+    let mut reader = encoding::encoder(coding, file);
+    loop {
+        let n = reader.read(buffer).await?;
+        if n == 0 { break }
+        res.write(&buffer[..n]).await?;
+    }
+    Ok(())
+}
+
+async fn stream_range<S:HttpSocket>(res: &mut S, file: &mut File, start: u64, length: u64, buffer: &mut [u8]) -> HttpResult<()> {
+    file.seek(SeekFrom::Start(start)).await?;
+    let mut left = length;
+    while left > 0 {
+        let want = left.min(buffer.len() as u64) as usize;
+        let n = file.read(&mut buffer[..want]).await?;
+        if n == 0 {
+            // the file shrunk after its metadata was read, the promised length cant be honored anymore
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "file truncated while streaming").into());
+        }
+        res.write(&buffer[..n]).await?;
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+pub async fn dir_handler<S:HttpSocket+Send>(shared: &SharedData, served: Served<'_>, client: &HttpClient, res: &mut S,path: &str) -> HttpResult<()> {
     let mut dir = fs::read_dir(&path).await?;
     let mut file: String = "".to_string();
+    let mut entries = vec![];
 
     println!("Path is dir {}", path);
 
@@ -175,6 +404,9 @@ pub async fn dir_handler<S:HttpSocket>(shared: &SharedData, res: S,path: &str) -
         let last_dir = file_parts.last().unwrap_or(&".");
         let meta = entry.metadata().await?;
         if !meta.is_file() {
+            if meta.is_dir() && shared.autoindex {
+                entries.push(autoindex::Entry{ name: file_name, is_dir: true, size: 0, modified: meta.modified().ok() });
+            }
             continue; // Mitigate dirs treated as files
         }
 
@@ -186,15 +418,39 @@ pub async fn dir_handler<S:HttpSocket>(shared: &SharedData, res: S,path: &str) -
             file = entry.path().to_string_lossy().to_string();
             break;
         }
+        if shared.autoindex {
+            entries.push(autoindex::Entry{ name: file_name, is_dir: false, size: meta.len(), modified: meta.modified().ok() });
+        }
     }
 
     println!("File found {}", file);
 
-    if fs::metadata(&file).await.unwrap().is_file(){
-        file_handler(shared,&file,res).await
-    } else {
-        error_handler(shared,409, std::io::Error::new(std::io::ErrorKind::IsADirectory,"Cannot find index file in directory"), res).await
+    match fs::metadata(&file).await{
+        Ok(meta) if meta.is_file()=>file_handler(shared, served, client,&file,&meta,res).await,
+        _ if shared.autoindex=>listing_handler(shared, served, client, entries, res).await,
+        _=>error_handler(shared, served, 409, std::io::Error::new(std::io::ErrorKind::IsADirectory,"Cannot find index file in directory"), res).await,
     }
 }
 
+pub async fn listing_handler<S:HttpSocket+Send>(shared: &SharedData, served: Served<'_>, client: &HttpClient, mut entries: Vec<autoindex::Entry>, res: &mut S) -> HttpResult<()> {
+    let head=client.method=="HEAD";
+    let json=autoindex::wants_json(client.headers.get("accept"));
+    let res=&mut WithHeaders::new(shared, client, served, if json { "application/json" } else { "text/html" }, res);
+    let base = client.path.split(['?', '#']).next().unwrap_or("/");
+    let base = if base.ends_with('/') { base.to_owned() } else { base.to_owned() + "/" };
+
+    // dotfiles stay reachable by name but arent advertised, site files arent served at all
+    entries.retain(|e|!e.name.starts_with('.'));
+    if shared.site_files.is_some() { entries.retain(|e|!site::is_site_file(&(base.clone()+&e.name))) }
+    let (key,desc)=autoindex::sort_from_query(&client.path);
+    autoindex::sort(&mut entries, key, desc);
 
+    let _=res.set_header("Vary", "Accept");
+    if json {
+        let _=res.set_header("Content-Type", "application/json");
+        res.close(body(head, autoindex::render_json(&base, &entries).as_bytes())).await
+    } else {
+        let _=res.set_header("Content-Type", "text/html; charset=utf-8");
+        res.close(body(head, autoindex::render_html(&base, &entries, key, desc).as_bytes())).await
+    }
+}
@@ -0,0 +1,394 @@
+use async_trait::async_trait;
+use rust_http::{common::{Compression, HttpClient, HttpResult, HttpSocket}, websocket::WebSocket};
+
+use crate::{compressible::pattern_matches, config::{Config, HeaderPreset, HeaderRule}, handlers::clean_path, structs::SharedData};
+
+/// the preset, `[headers]` and `[[header_rules]]`, resolved per response by path and content type
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRules{
+    // preset overridden by `[headers]`, the same for every response
+    base: Vec<(String,String)>,
+    // Strict-Transport-Security of the preset, only for responses over tls
+    hsts: Option<String>,
+    rules: Vec<HeaderRule>,
+}
+
+impl HeaderRules{
+    pub fn new(config: &Config)->Self{
+        let security=&config.security_headers;
+        let mut base=vec![];
+        let mut hsts=None;
+        if security.preset==HeaderPreset::Secure {
+            if security.hsts_max_age>0 {
+                let subdomains=if security.hsts_include_subdomains { "; includeSubDomains" } else { "" };
+                hsts=Some(format!("max-age={}{subdomains}",security.hsts_max_age));
+            }
+            set(&mut base, "X-Content-Type-Options", "nosniff");
+            if !security.content_security_policy.is_empty() { set(&mut base, "Content-Security-Policy", &security.content_security_policy) }
+            if !security.referrer_policy.is_empty() { set(&mut base, "Referrer-Policy", &security.referrer_policy) }
+        }
+        for (name,value) in &config.headers{ set(&mut base, name, value) }
+        Self{ base, hsts, rules: config.header_rules.clone() }
+    }
+
+    /// applies the headers for a response to `path` of type `mime` on top of `headers`. every
+    /// matching rule applies in order, removing before setting, so a later rule overrides an earlier one.
+    /// HSTS is only sent over `tls`, a plain listener of the same server mustnt promise https
+    pub fn apply(&self, path: &str, mime: &str, tls: bool, headers: &mut Vec<(String,String)>){
+        let mime=mime.split(';').next().unwrap_or_default().trim();
+        if let Some(hsts)=self.hsts.as_ref().filter(|_|tls) { set(headers, "Strict-Transport-Security", hsts) }
+        for (name,value) in &self.base{ set(headers, name, value) }
+        for rule in &self.rules{
+            if !rule.path.as_deref().is_none_or(|p|glob_matches(p, path)) { continue }
+            if !rule.mime.is_empty() && !rule.mime.iter().any(|p|pattern_matches(p, mime)) { continue }
+            headers.retain(|(name,_)|!rule.remove.iter().any(|r|r.eq_ignore_ascii_case(name)));
+            for (name,value) in &rule.set{ set(headers, name, value) }
+        }
+    }
+}
+
+/// the site a response is served from and whether its connection is tls, both decide headers
+#[derive(Debug, Clone, Copy)]
+pub struct Served<'a>{
+    pub serve_dir: &'a str,
+    pub tls: bool,
+}
+
+/// holds back the headers a handler sets until the response starts, then applies the configured
+/// ones and `_headers` over them. that way a rule can replace or remove Content-Type, ETag and the like
+pub struct WithHeaders<'a,S>{
+    inner: &'a mut S,
+    shared: &'a SharedData,
+    client: &'a HttpClient,
+    served: Served<'a>,
+    // what the resource is, a range response says multipart/byteranges instead
+    mime: &'a str,
+    headers: Vec<(String,String)>,
+    sent: bool,
+}
+
+impl<'a,S:HttpSocket> WithHeaders<'a,S>{
+    pub fn new(shared: &'a SharedData, client: &'a HttpClient, served: Served<'a>, mime: &'a str, inner: &'a mut S)->Self{
+        Self{ inner, shared, client, served, mime, headers: vec![], sent: false }
+    }
+
+    async fn send_headers(&mut self){
+        if self.sent { return }
+        self.sent=true;
+        let path=clean_path(&self.client.path);
+        let mut headers=std::mem::take(&mut self.headers);
+        self.shared.headers.apply(&path, self.mime, self.served.tls, &mut headers);
+        if let Some(site)=&self.shared.site_files {
+            for (name,value) in site.get(self.served.serve_dir).await.headers(&path){ set(&mut headers, &name, &value) }
+        }
+        for (name,value) in headers{
+            let _=self.inner.set_header(&name, &value);
+        }
+    }
+}
+
+#[async_trait]
+impl<S:HttpSocket+Send> HttpSocket for WithHeaders<'_,S>{
+    type Stream=S::Stream;
+
+    async fn get_client(&mut self)->HttpResult<&HttpClient>{
+        self.inner.get_client().await
+    }
+
+    async fn read_client(&mut self)->HttpResult<&HttpClient>{
+        self.inner.read_client().await
+    }
+
+    fn set_header(&mut self, header: &str, value: &str)->HttpResult<()>{
+        set(&mut self.headers, header, value);
+        Ok(())
+    }
+
+    fn set_status(&mut self, code: u16, msg: String)->HttpResult<()>{
+        self.inner.set_status(code, msg)
+    }
+
+    fn set_compression(&mut self, c: Compression)->HttpResult<()>{
+        self.inner.set_compression(c)
+    }
+
+    async fn write(&mut self, body: &[u8])->HttpResult<()>{
+        self.send_headers().await;
+        self.inner.write(body).await
+    }
+
+    async fn close(&mut self, body: &[u8])->HttpResult<()>{
+        self.send_headers().await;
+        self.inner.close(body).await
+    }
+
+    async fn websocket(&mut self)->HttpResult<WebSocket<Self::Stream>>{
+        self.send_headers().await;
+        self.inner.websocket().await
+    }
+}
+
+// header names are case insensitive, the last value set wins
+fn set(headers: &mut Vec<(String,String)>, name: &str, value: &str){
+    headers.retain(|(n,_)|!n.eq_ignore_ascii_case(name));
+    headers.push((name.to_owned(),value.to_owned()));
+}
+
+/// `*` and `?` stay within one path segment, `**` crosses them and `/**/` also matches a single `/`
+pub fn glob_matches(pattern: &str, path: &str)->bool{
+    glob(pattern.as_bytes(), path.as_bytes())
+}
+
+fn glob(pattern: &[u8], path: &[u8])->bool{
+    match pattern{
+        []=>path.is_empty(),
+        [b'*',b'*',rest @ ..]=>{
+            if let Some(after)=rest.strip_prefix(b"/") {
+                if glob(after, path) { return true }
+            }
+            (0..=path.len()).any(|i|glob(rest, &path[i..]))
+        },
+        [b'*',rest @ ..]=>{
+            let segment=path.iter().position(|b|*b==b'/').unwrap_or(path.len());
+            (0..=segment).any(|i|glob(rest, &path[i..]))
+        },
+        [b'?',rest @ ..]=>matches!(path, [c,tail @ ..] if *c!=b'/' && glob(rest, tail)),
+        [c,rest @ ..]=>matches!(path, [p,tail @ ..] if p==c && glob(rest, tail)),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::collections::BTreeMap;
+    use tokio::io::DuplexStream;
+    use crate::structs;
+
+    #[test]
+    fn globs(){
+        let cases=[
+            ("/index.html","/index.html",true),
+            ("/index.html","/index.htm",false),
+            ("/*.html","/index.html",true),
+            ("/*.html","/docs/index.html",false),
+            ("/*","/",true),
+            ("/docs/*","/docs/a/b",false),
+            ("/assets/**","/assets/a/b/c.js",true),
+            ("/assets/**","/assets/",true),
+            ("/assets/**","/assets",false),
+            ("/**/*.html","/index.html",true),
+            ("/**/*.html","/a/b/index.html",true),
+            ("/**/*.html","/a/b/index.css",false),
+            ("/**","/",true),
+            ("/file?.txt","/file1.txt",true),
+            ("/file?.txt","/file.txt",false),
+            ("/a?b","/a/b",false),
+            ("/a*b","/a/b",false),
+            ("/a**b","/a/x/b",true),
+        ];
+        for (pattern,path,expected) in cases{
+            assert_eq!(glob_matches(pattern, path), expected, "{pattern} against {path}");
+        }
+    }
+
+    fn rule(path: Option<&str>, mime: &[&str], remove: &[&str], set: &[(&str,&str)])->HeaderRule{
+        HeaderRule{
+            path: path.map(str::to_owned),
+            mime: mime.iter().map(|m|m.to_string()).collect(),
+            remove: remove.iter().map(|r|r.to_string()).collect(),
+            set: set.iter().map(|(n,v)|(n.to_string(),v.to_string())).collect::<BTreeMap<_,_>>(),
+        }
+    }
+
+    fn rules(rules: Vec<HeaderRule>)->HeaderRules{
+        let mut config=Config::default();
+        config.headers.insert("X-Frame-Options".to_owned(), "DENY".to_owned());
+        config.header_rules=rules;
+        HeaderRules::new(&config)
+    }
+
+    fn get<'a>(headers: &'a [(String,String)], name: &str)->Option<&'a str>{
+        headers.iter().find(|(n,_)|n.eq_ignore_ascii_case(name)).map(|(_,v)|v.as_str())
+    }
+
+    #[test]
+    fn preset_is_opt_in(){
+        let mut headers=vec![];
+        HeaderRules::new(&Config::default()).apply("/", "text/html", true, &mut headers);
+        assert!(headers.is_empty());
+
+        let mut config=Config::default();
+        config.security_headers.preset=HeaderPreset::Secure;
+        HeaderRules::new(&config).apply("/", "text/html", true, &mut headers);
+        assert_eq!(get(&headers, "x-content-type-options"), Some("nosniff"));
+        assert_eq!(get(&headers, "strict-transport-security"), Some("max-age=31536000"));
+
+        // a plain listener of a server that also has tls
+        let mut headers=vec![];
+        HeaderRules::new(&config).apply("/", "text/html", false, &mut headers);
+        assert_eq!(get(&headers, "x-content-type-options"), Some("nosniff"));
+        assert_eq!(get(&headers, "strict-transport-security"), None);
+    }
+
+    #[test]
+    fn rules_apply_over_the_response(){
+        let rules=rules(vec![
+            rule(Some("/feed.xml"), &[], &["etag"], &[("Content-Type","application/rss+xml")]),
+            rule(None, &["text/*"], &[], &[("Cache-Control","no-cache")]),
+            rule(Some("/assets/**"), &[], &[], &[("Cache-Control","immutable")]),
+        ]);
+        let response=||vec![("Content-Type".to_owned(),"text/xml".to_owned()),("ETag".to_owned(),"\"abc\"".to_owned())];
+
+        let mut headers=response();
+        rules.apply("/feed.xml", "text/xml; charset=utf-8", false, &mut headers);
+        assert_eq!(get(&headers, "content-type"), Some("application/rss+xml"));
+        assert_eq!(get(&headers, "etag"), None);
+        assert_eq!(get(&headers, "cache-control"), Some("no-cache"));
+        assert_eq!(get(&headers, "x-frame-options"), Some("DENY"));
+
+        let mut headers=response();
+        rules.apply("/assets/site.css", "text/css", false, &mut headers);
+        assert_eq!(get(&headers, "content-type"), Some("text/xml"));
+        assert_eq!(get(&headers, "etag"), Some("\"abc\""));
+        // later rules win
+        assert_eq!(get(&headers, "cache-control"), Some("immutable"));
+
+        let mut headers=response();
+        rules.apply("/logo.png", "image/png", false, &mut headers);
+        assert_eq!(get(&headers, "cache-control"), None);
+    }
+
+    // remembers what reaches the connection
+    struct Recorder{
+        client: HttpClient,
+        status: u16,
+        headers: Vec<(String,String)>,
+        body: Vec<u8>,
+    }
+
+    #[async_trait]
+    impl HttpSocket for Recorder{
+        type Stream=DuplexStream;
+
+        async fn get_client(&mut self)->HttpResult<&HttpClient>{ Ok(&self.client) }
+        async fn read_client(&mut self)->HttpResult<&HttpClient>{ Ok(&self.client) }
+        fn set_header(&mut self, header: &str, value: &str)->HttpResult<()>{
+            set(&mut self.headers, header, value);
+            Ok(())
+        }
+        fn set_status(&mut self, code: u16, _msg: String)->HttpResult<()>{
+            self.status=code;
+            Ok(())
+        }
+        fn set_compression(&mut self, _c: Compression)->HttpResult<()>{ Ok(()) }
+        async fn write(&mut self, body: &[u8])->HttpResult<()>{
+            self.body.extend_from_slice(body);
+            Ok(())
+        }
+        async fn close(&mut self, body: &[u8])->HttpResult<()>{ self.write(body).await }
+        async fn websocket(&mut self)->HttpResult<WebSocket<Self::Stream>>{
+            Err(std::io::Error::other("no websockets").into())
+        }
+    }
+
+    impl Recorder{
+        fn new(client: HttpClient)->Self{
+            Self{ client, status: 200, headers: vec![], body: vec![] }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_rule_overrides_the_file_handler(){
+        let dir=std::env::temp_dir().join(format!("static-serve-headers-{}",std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file=dir.join("feed.xml");
+        std::fs::write(&file, "<rss/>").unwrap();
+
+        let mut shared=structs::tests::shared();
+        shared.mime=[("xml","text/xml")].into_iter().collect();
+        shared.headers=rules(vec![rule(Some("/feed.xml"), &[], &["ETag"], &[("Content-Type","application/rss+xml")])]);
+        let mut client=HttpClient::empty();
+        client.method="GET".to_owned();
+        client.path="/feed.xml".to_owned();
+        let mut res=Recorder::new(client.clone());
+
+        let path=file.to_string_lossy();
+        let meta=std::fs::metadata(&file).unwrap();
+        crate::handlers::file_handler(&shared, Served{ serve_dir: "", tls: false }, &client, &path, &meta, &mut res).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(get(&res.headers, "content-type"), Some("application/rss+xml"));
+        assert_eq!(get(&res.headers, "etag"), None);
+        assert_eq!(get(&res.headers, "content-length"), Some("6"));
+        assert_eq!(get(&res.headers, "x-frame-options"), Some("DENY"));
+        assert_eq!(res.body, b"<rss/>");
+    }
+
+    #[tokio::test]
+    async fn site_headers_follow_the_served_site(){
+        let dir=std::env::temp_dir().join(format!("static-serve-sni-{}",std::process::id()));
+        for site in ["a","public"]{
+            std::fs::create_dir_all(dir.join(site)).unwrap();
+            std::fs::write(dir.join(site).join("index.html"), site).unwrap();
+            std::fs::write(dir.join(site).join("_headers"), format!("/*\n  X-Site: {site}\n")).unwrap();
+        }
+        let mut shared=structs::tests::shared();
+        shared.serve_dir=dir.join("public").to_string_lossy().to_string();
+        shared.vhosts=crate::vhost::HostTable::new();
+        shared.vhosts.insert("a.test", dir.join("a").to_string_lossy().to_string());
+        shared.site_files=Some(Default::default());
+        let shared=std::sync::Arc::new(shared);
+
+        let request=|host: Option<&str>, sni: Option<&str>|{
+            let mut client=HttpClient::empty();
+            client.method="GET".to_owned();
+            client.path="/index.html".to_owned();
+            if let Some(host)=host { client.headers.insert("host".to_owned(), vec![host.to_owned()]); }
+            let session=crate::middleware::MiddlewareData::<DuplexStream>::secure().with_session(None, sni.map(str::to_owned));
+            (Recorder::new(client),std::sync::Arc::new(session))
+        };
+        let mut answers=vec![];
+        for (host,sni) in [(None,Some("a.test")),(Some("a.test"),None),(Some("other.test"),Some("a.test")),(None,None)]{
+            let (mut res,session)=request(host, sni);
+            crate::handlers::handler(std::sync::Arc::clone(&shared), session, &mut res).await.unwrap();
+            answers.push((res.status,get(&res.headers, "x-site").map(str::to_owned)));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let site=|s: &str|Some(s.to_owned());
+        assert_eq!(answers, vec![
+            // sni without Host serves a.test, and its _headers come along
+            (200,site("a")),
+            (200,site("a")),
+            // Host and sni disagree, nothing of a.test is used
+            (421,site("public")),
+            (200,site("public")),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn hsts_only_over_tls(){
+        let dir=std::env::temp_dir().join(format!("static-serve-hsts-{}",std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("index.html"), "hi").unwrap();
+        let mut config=Config::default();
+        config.security_headers.preset=HeaderPreset::Secure;
+        let mut shared=structs::tests::shared();
+        shared.serve_dir=dir.to_string_lossy().to_string();
+        shared.headers=HeaderRules::new(&config);
+        let shared=std::sync::Arc::new(shared);
+
+        let mut hsts=vec![];
+        for session in [crate::middleware::MiddlewareData::<DuplexStream>::empty(),crate::middleware::MiddlewareData::secure()]{
+            let mut client=HttpClient::empty();
+            client.method="GET".to_owned();
+            client.path="/index.html".to_owned();
+            let mut res=Recorder::new(client);
+            crate::handlers::handler(std::sync::Arc::clone(&shared), std::sync::Arc::new(session), &mut res).await.unwrap();
+            assert_eq!(get(&res.headers, "x-content-type-options"), Some("nosniff"));
+            hsts.push(get(&res.headers, "strict-transport-security").map(str::to_owned));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(hsts, vec![None,Some("max-age=31536000".to_owned())]);
+    }
+}
@@ -0,0 +1,274 @@
+use std::{io::{Error, ErrorKind}, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
+use h3::server::RequestStream;
+use http::{Request, Response, StatusCode};
+use quinn::crypto::rustls::QuicServerConfig;
+use rust_http::{common::{Compression, HttpClient, HttpError, HttpResult, HttpSocket}, websocket::WebSocket};
+use rustls::{Certificate, PrivateKey};
+use rustls23::{pki_types::{CertificateDer, PrivateKeyDer}, server::{danger::ClientCertVerifier, ClientHello, ResolvesServerCert, WebPkiClientVerifier}, sign::CertifiedKey, RootCertStore};
+use tokio::io::DuplexStream;
+
+use crate::{clientauth::{self, ClientCert}, config::{ClientAuthConfig, ClientAuthMode}, vhost::{Certs, HostTable, SniResolver}};
+
+// only meaningful for a single http/1.1 connection, http/3 forbids sending them
+const CONNECTION_HEADERS: [&str; 5]=["connection","keep-alive","proxy-connection","transfer-encoding","upgrade"];
+
+/// request bodies past this are refused with 413. the handlers serve files and never
+/// need a body, so this only bounds what a client can make a stream buffer
+pub const MAX_BODY_SIZE: usize=1024*1024;
+
+fn h3_err(e: impl std::fmt::Display)->HttpError{
+    Error::other(e.to_string()).into()
+}
+
+fn certified_key((key,certs): &(PrivateKey,Vec<Certificate>))->std::io::Result<Arc<CertifiedKey>>{
+    let certs=certs.iter().map(|c|CertificateDer::from(c.0.clone())).collect();
+    let key=PrivateKeyDer::try_from(key.0.clone()).map_err(|e|Error::new(ErrorKind::InvalidData, e))?;
+    let key=rustls23::crypto::ring::sign::any_supported_type(&key).map_err(|e|Error::new(ErrorKind::InvalidData, e))?;
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+impl ResolvesServerCert for SniResolver<CertifiedKey>{
+    fn resolve(&self, client_hello: ClientHello<'_>)->Option<Arc<CertifiedKey>>{
+        self.lookup(client_hello.server_name())
+    }
+}
+
+// the same client certificate policy as `clientauth::verifier`, for the newer rustls
+fn client_verifier(config: &ClientAuthConfig)->std::io::Result<Arc<dyn ClientCertVerifier>>{
+    if config.mode==ClientAuthMode::None { return Ok(WebPkiClientVerifier::no_client_auth()) }
+    let mut roots=RootCertStore::empty();
+    for cert in clientauth::load_ca(&config.ca_path).map_err(|e|Error::new(ErrorKind::InvalidData, e))?{
+        roots.add(CertificateDer::from(cert.0)).map_err(|e|Error::new(ErrorKind::InvalidData, e))?;
+    }
+    let builder=WebPkiClientVerifier::builder(Arc::new(roots));
+    let builder=if config.mode==ClientAuthMode::Optional { builder.allow_unauthenticated() } else { builder };
+    builder.build().map_err(|e|Error::new(ErrorKind::InvalidData, e))
+}
+
+/// the verified client certificate of a quic connection
+pub fn peer_cert(conn: &quinn::Connection)->Option<ClientCert>{
+    let chain=conn.peer_identity()?.downcast::<Vec<CertificateDer<'static>>>().ok()?;
+    ClientCert::from_chain(&chain.iter().map(|c|Certificate(c.to_vec())).collect::<Vec<_>>())
+}
+
+/// the sni name a quic connection was opened for
+pub fn server_name(conn: &quinn::Connection)->Option<String>{
+    conn.handshake_data()?.downcast::<quinn::crypto::rustls::HandshakeData>().ok()?.server_name
+}
+
+/// h3 over quic with the same keys, certificates and client auth as the tcp listener
+pub fn server_config(certs: &Certs, client_auth: &ClientAuthConfig)->std::io::Result<quinn::ServerConfig>{
+    let mut hosts=HostTable::new();
+    for (pattern,pair) in certs.hosts.iter(){
+        hosts.insert(pattern, certified_key(pair)?);
+    }
+    let default=certs.default.as_ref().map(certified_key).transpose()?;
+    let mut tls=rustls23::ServerConfig::builder()
+        .with_client_cert_verifier(client_verifier(client_auth)?)
+        .with_cert_resolver(Arc::new(SniResolver{ hosts, default }));
+    tls.alpn_protocols=vec![b"h3".to_vec()];
+
+    let crypto=QuicServerConfig::try_from(tls).map_err(|e|Error::new(ErrorKind::InvalidData, e))?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+pub fn endpoint(addr: SocketAddr, certs: &Certs, client_auth: &ClientAuthConfig)->std::io::Result<quinn::Endpoint>{
+    quinn::Endpoint::server(server_config(certs, client_auth)?, addr)
+}
+
+/// one http/3 request stream behind the same interface as http/1.1 and http/2,
+/// so it goes through `handlers::handler` unchanged
+pub struct H3Socket{
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    client: HttpClient,
+    body_read: bool,
+    too_large: bool,
+    status: u16,
+    headers: Vec<(String,String)>,
+    head_sent: bool,
+}
+
+impl H3Socket{
+    pub fn new(req: Request<()>, stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>)->Self{
+        let mut client=HttpClient::empty();
+        client.method=req.method().to_string();
+        client.path=req.uri().path_and_query().map_or("/".to_owned(),|p|p.to_string());
+        client.version="HTTP/3".to_owned();
+        for (name,value) in req.headers(){
+            client.headers.entry(name.as_str().to_owned()).or_default().push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+        }
+        // :authority takes the place of the host header
+        if let Some(authority)=req.uri().authority(){
+            client.headers.entry("host".to_owned()).or_insert_with(||vec![authority.to_string()]);
+        }
+        Self{ stream, client, body_read: false, too_large: false, status: 200, headers: vec![], head_sent: false }
+    }
+
+    /// whether `read_client` gave up because the body exceeded `MAX_BODY_SIZE`
+    pub fn too_large(&self)->bool{
+        self.too_large
+    }
+
+    pub async fn reject_too_large(&mut self)->HttpResult<()>{
+        self.headers.clear();
+        self.status=413;
+        self.set_header("Content-Type", "text/plain")?;
+        let head=self.client.method=="HEAD";
+        self.close(if head { b"" } else { b"content too large" }).await
+    }
+
+    async fn send_head(&mut self)->HttpResult<()>{
+        if self.head_sent { return Ok(()) }
+        let mut res=Response::builder().status(StatusCode::from_u16(self.status).map_err(h3_err)?);
+        for (name,value) in &self.headers{
+            if CONNECTION_HEADERS.contains(&name.to_lowercase().as_str()) { continue }
+            res=res.header(name.as_str(), value.as_str());
+        }
+        self.stream.send_response(res.body(()).map_err(h3_err)?).await.map_err(h3_err)?;
+        self.head_sent=true;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl HttpSocket for H3Socket{
+    // never used, there are no websockets over http/3
+    type Stream=DuplexStream;
+
+    async fn get_client(&mut self)->HttpResult<&HttpClient>{
+        Ok(&self.client)
+    }
+
+    /// the headers arrive with the stream, this only collects the body
+    async fn read_client(&mut self)->HttpResult<&HttpClient>{
+        if !self.body_read{
+            self.body_read=true;
+            // a declared length is refused before anything is buffered
+            let declared=self.client.headers.get("content-length").and_then(|l|l[0].trim().parse::<u64>().ok());
+            if declared.is_some_and(|l|l>MAX_BODY_SIZE as u64) {
+                self.too_large=true;
+                return Err(Error::new(ErrorKind::InvalidData, "request body too large").into());
+            }
+            while let Some(mut chunk)=self.stream.recv_data().await.map_err(h3_err)?{
+                if self.client.body.len()+chunk.remaining()>MAX_BODY_SIZE {
+                    self.too_large=true;
+                    return Err(Error::new(ErrorKind::InvalidData, "request body too large").into());
+                }
+                let data=chunk.copy_to_bytes(chunk.remaining());
+                self.client.body.extend_from_slice(&data);
+            }
+        }
+        Ok(&self.client)
+    }
+
+    fn set_header(&mut self, header: &str, value: &str)->HttpResult<()>{
+        self.headers.retain(|(n,_)|!n.eq_ignore_ascii_case(header));
+        self.headers.push((header.to_owned(),value.to_owned()));
+        Ok(())
+    }
+
+    fn set_status(&mut self, code: u16, _msg: String)->HttpResult<()>{
+        self.status=code;
+        Ok(())
+    }
+
+    /// responses are sent as the handler produced them, quic streams have no transparent compression
+    fn set_compression(&mut self, _c: Compression)->HttpResult<()>{
+        Ok(())
+    }
+
+    async fn write(&mut self, body: &[u8])->HttpResult<()>{
+        self.send_head().await?;
+        if !body.is_empty(){
+            self.stream.send_data(Bytes::copy_from_slice(body)).await.map_err(h3_err)?;
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self, body: &[u8])->HttpResult<()>{
+        self.write(body).await?;
+        self.stream.finish().await.map_err(h3_err)
+    }
+
+    async fn websocket(&mut self)->HttpResult<WebSocket<Self::Stream>>{
+        Err(Error::new(ErrorKind::Unsupported, "websockets arent available over http/3").into())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use bytes::BytesMut;
+    use quinn::crypto::rustls::QuicClientConfig;
+
+    // answers one request with the length of its body, or 413
+    async fn serve_one(endpoint: quinn::Endpoint){
+        let conn=endpoint.accept().await.unwrap().await.unwrap();
+        let mut h3=h3::server::Connection::new(h3_quinn::Connection::new(conn)).await.unwrap();
+        let (req,stream)=h3.accept().await.unwrap().unwrap().resolve_request().await.unwrap();
+        let mut hand=H3Socket::new(req, stream);
+        let read=hand.read_client().await.map(|c|c.body.len());
+        match read{
+            Ok(len)=>hand.close(len.to_string().as_bytes()).await.unwrap(),
+            Err(_) if hand.too_large()=>hand.reject_too_large().await.unwrap(),
+            Err(e)=>panic!("{e:?}"),
+        }
+        // keeps the connection up until the client has its answer
+        while let Ok(Some(_))=h3.accept().await {}
+    }
+
+    async fn post(body: &[u8], content_length: Option<usize>)->(u16,String){
+        let (key,cert)=crate::devcert::generate(&["localhost".to_owned()]).unwrap();
+        let pair=crate::devcert::parse_pair(&key, &cert).unwrap();
+        let certs=Certs{ default: Some(pair.clone()), hosts: HostTable::new() };
+        let server=endpoint("127.0.0.1:0".parse().unwrap(), &certs, &ClientAuthConfig::default()).unwrap();
+        let addr=server.local_addr().unwrap();
+        let served=tokio::spawn(serve_one(server));
+
+        let mut roots=RootCertStore::empty();
+        roots.add(CertificateDer::from(pair.1[0].0.clone())).unwrap();
+        let mut tls=rustls23::ClientConfig::builder_with_provider(Arc::new(rustls23::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols=vec![b"h3".to_vec()];
+        let mut client=quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls).unwrap())));
+        let conn=client.connect(addr, "localhost").unwrap().await.unwrap();
+        let (mut driver,mut send)=h3::client::new(h3_quinn::Connection::new(conn)).await.unwrap();
+        tokio::spawn(async move { driver.wait_idle().await });
+
+        let mut req=Request::post(format!("https://localhost:{}/upload",addr.port()));
+        if let Some(len)=content_length { req=req.header("content-length", len) }
+        let mut stream=send.send_request(req.body(()).unwrap()).await.unwrap();
+        let _=stream.send_data(Bytes::copy_from_slice(body)).await;
+        let _=stream.finish().await;
+        let res=stream.recv_response().await.unwrap();
+        let mut text=BytesMut::new();
+        while let Some(mut chunk)=stream.recv_data().await.unwrap(){
+            text.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+        }
+        drop(send);
+        served.await.unwrap();
+        (res.status().as_u16(),String::from_utf8_lossy(&text).into_owned())
+    }
+
+    #[tokio::test]
+    async fn reads_the_body(){
+        assert_eq!(post(b"hello", Some(5)).await, (200,"5".to_owned()));
+        assert_eq!(post(&vec![b'x'; MAX_BODY_SIZE], None).await, (200,MAX_BODY_SIZE.to_string()));
+    }
+
+    #[tokio::test]
+    async fn refuses_declared_oversized_bodies(){
+        assert_eq!(post(b"", Some(MAX_BODY_SIZE+1)).await, (413,"content too large".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_bodies_while_reading(){
+        assert_eq!(post(&vec![b'x'; MAX_BODY_SIZE+1], None).await, (413,"content too large".to_owned()));
+    }
+}
@@ -0,0 +1,196 @@
+use std::{env, io::{Error, ErrorKind}, net::{SocketAddr, ToSocketAddrs}, sync::{atomic::{AtomicBool, Ordering}, OnceLock}};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::config::{Config, ListenEndpoint};
+
+// systemd passes sockets starting at this descriptor
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32=3;
+
+// how many sockets systemd passed, read by `take_systemd_env`
+static SYSTEMD_FDS: OnceLock<i32>=OnceLock::new();
+// the passed descriptors can only be owned by one listener
+static SYSTEMD_TAKEN: AtomicBool=AtomicBool::new(false);
+
+enum Bound{
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+pub enum Conn{
+    Tcp(TcpStream,SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// a bound socket and the protocols its connections get
+pub struct Listener{
+    bound: Bound,
+    pub name: String,
+    pub tls: bool,
+    pub http2: bool,
+    // unix socket files we created are removed again when the listener is dropped
+    cleanup: Option<String>,
+}
+
+impl Listener{
+    pub async fn accept(&self)->std::io::Result<Conn>{
+        match &self.bound{
+            Bound::Tcp(l)=>l.accept().await.map(|(s,a)|Conn::Tcp(s,a)),
+            #[cfg(unix)]
+            Bound::Unix(l)=>l.accept().await.map(|(s,_)|Conn::Unix(s)),
+        }
+    }
+
+    pub fn tcp_addr(&self)->Option<SocketAddr>{
+        match &self.bound{
+            Bound::Tcp(l)=>l.local_addr().ok(),
+            #[cfg(unix)]
+            Bound::Unix(_)=>None,
+        }
+    }
+}
+
+impl Drop for Listener{
+    fn drop(&mut self){
+        if let Some(path)=&self.cleanup{ let _=std::fs::remove_file(path); }
+    }
+}
+
+/// `listener.endpoints`. without any, the sockets of systemd socket activation
+/// when there are some and `listener.address` otherwise
+pub fn endpoints(config: &Config)->Vec<ListenEndpoint>{
+    if !config.listener.endpoints.is_empty() { return config.listener.endpoints.clone() }
+    if systemd_fd_count()>0 {
+        return vec![ListenEndpoint{ systemd: true, ..ListenEndpoint::default() }];
+    }
+    vec![ListenEndpoint{ address: Some(config.listener.address.clone()), ..ListenEndpoint::default() }]
+}
+
+pub fn bind_all(config: &Config)->std::io::Result<Vec<Listener>>{
+    let mut listeners=vec![];
+    for endpoint in endpoints(config){
+        let tls=endpoint.tls.unwrap_or(config.tls.enabled);
+        let http2=config.http2.enabled && endpoint.http2.unwrap_or(true);
+        let listener=|bound,name,cleanup|Listener{ bound, name, tls, http2, cleanup };
+
+        if let Some(address)=&endpoint.address{
+            let tcp=bind_tcp(address, endpoint.ipv6_only)?;
+            listeners.push(listener(Bound::Tcp(TcpListener::from_std(tcp)?), address.clone(), None));
+        } else if let Some(path)=&endpoint.unix{
+            #[cfg(unix)]
+            {
+                let unix=bind_unix(path, endpoint.mode.as_deref())?;
+                listeners.push(listener(Bound::Unix(UnixListener::from_std(unix)?), format!("unix:{path}"), Some(path.clone())));
+            }
+            #[cfg(not(unix))]
+            return Err(Error::new(ErrorKind::Unsupported, format!("unix socket {path} isnt supported here")));
+        } else if endpoint.systemd{
+            for (bound,name) in systemd_sockets()?{
+                listeners.push(listener(bound, name, None));
+            }
+        }
+    }
+    if listeners.is_empty() { return Err(Error::new(ErrorKind::InvalidInput, "nothing to listen on")) }
+    Ok(listeners)
+}
+
+// the first address that binds, like tokio's bind. ipv6 wildcards are dual stack unless `ipv6_only`
+fn bind_tcp(address: &str, ipv6_only: bool)->std::io::Result<std::net::TcpListener>{
+    let mut last=Error::new(ErrorKind::InvalidInput, format!("{address} resolved to nothing"));
+    for addr in address.to_socket_addrs()?{
+        let bind=||->std::io::Result<std::net::TcpListener>{
+            let socket=Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+            if addr.is_ipv6() { socket.set_only_v6(ipv6_only)? }
+            #[cfg(unix)]
+            socket.set_reuse_address(true)?;
+            socket.bind(&addr.into())?;
+            socket.listen(1024)?;
+            socket.set_nonblocking(true)?;
+            Ok(socket.into())
+        };
+        match bind(){
+            Ok(l)=>return Ok(l),
+            Err(e)=>last=e,
+        }
+    }
+    Err(last)
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str, mode: Option<&str>)->std::io::Result<std::os::unix::net::UnixListener>{
+    use std::os::unix::{fs::FileTypeExt, net::UnixStream};
+
+    // left behind by a previous run that didnt shut down cleanly. a socket something still
+    // accepts on belongs to a running server and is left alone, bind reports it as in use
+    if std::fs::symlink_metadata(path).is_ok_and(|m|m.file_type().is_socket()) {
+        match UnixStream::connect(path){
+            Err(e) if e.kind()==ErrorKind::ConnectionRefused=>std::fs::remove_file(path)?,
+            _=>(),
+        }
+    }
+    let mode=mode.map(|m|u32::from_str_radix(m, 8).map_err(|_|Error::new(ErrorKind::InvalidInput, format!("mode {m} isnt octal")))).transpose()?;
+    // the socket file gets its mode at creation, there is no moment it is more open than asked for.
+    // the umask is process wide, this runs at startup before anything else creates files
+    let previous=mode.map(|m|unsafe{ libc::umask(0o777 & !m as libc::mode_t) });
+    let listener=std::os::unix::net::UnixListener::bind(path);
+    if let Some(previous)=previous{ unsafe{ libc::umask(previous); } }
+    let listener=listener?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// reads and clears the socket activation variables, so nothing we start inherits them.
+/// changing the environment is only sound while the process has a single thread,
+/// this has to run first thing in main before the runtime starts
+pub fn take_systemd_env(){
+    // LISTEN_FDS only counts when LISTEN_PID names this process
+    let pid=env::var("LISTEN_PID").ok().and_then(|p|p.parse::<u32>().ok());
+    let count=if pid==Some(std::process::id()) { env::var("LISTEN_FDS").ok().and_then(|n|n.parse().ok()).unwrap_or(0) } else { 0 };
+    let _=SYSTEMD_FDS.set(count);
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+}
+
+fn systemd_fd_count()->i32{
+    SYSTEMD_FDS.get().copied().unwrap_or(0)
+}
+
+#[cfg(unix)]
+fn systemd_sockets()->std::io::Result<Vec<(Bound,String)>>{
+    use std::os::fd::FromRawFd;
+
+    let count=systemd_fd_count();
+    if count==0 { return Err(Error::new(ErrorKind::NotFound, "no sockets passed through LISTEN_FDS")) }
+    if SYSTEMD_TAKEN.swap(true, Ordering::SeqCst) {
+        return Err(Error::new(ErrorKind::AlreadyExists, "the systemd sockets are already used by another endpoint"));
+    }
+
+    let mut sockets=vec![];
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START+count{
+        // systemd hands these over for us to own, nothing else in the process uses them
+        let socket=unsafe{ Socket::from_raw_fd(fd) };
+        socket.set_nonblocking(true)?;
+        let local=socket.local_addr()?;
+        match local.as_socket(){
+            Some(addr)=>sockets.push((Bound::Tcp(TcpListener::from_std(socket.into())?),format!("systemd fd {fd} {addr}"))),
+            None if local.is_unix()=>{
+                let name=local.as_pathname().map_or(format!("systemd fd {fd}"),|p|format!("systemd fd {fd} unix:{}",p.display()));
+                sockets.push((Bound::Unix(UnixListener::from_std(socket.into())?),name));
+            },
+            None=>return Err(Error::new(ErrorKind::Unsupported, format!("systemd fd {fd} is neither tcp nor a unix socket"))),
+        }
+    }
+    Ok(sockets)
+}
+
+#[cfg(not(unix))]
+fn systemd_sockets()->std::io::Result<Vec<(Bound,String)>>{
+    Err(Error::new(ErrorKind::Unsupported, "systemd socket activation needs unix"))
+}
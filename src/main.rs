@@ -2,179 +2,198 @@ mod mime_map;
 mod handlers;
 mod structs;
 mod middleware;
+mod range;
+mod conditional;
+mod autoindex;
+mod encoding;
+mod compressible;
+mod config;
+mod shutdown;
+mod http3;
+mod vhost;
+mod tls;
+mod devcert;
+mod clientauth;
+mod acme;
+mod redirect;
+mod listen;
+mod headers;
+mod site;
 
 use rust_http::{
-    common::{HttpConstructor, /*HttpError,*/ HttpResult, HttpSocket, Stream}, http1::handler::Http1Socket, http2::{Http2FrameSettings, Http2FrameType, Http2Handler, Http2Session}
+    common::{HttpClient, HttpConstructor, /*HttpError,*/ HttpResult, HttpSocket, Stream}, http1::handler::Http1Socket, http2::{Http2Frame, Http2Handler, Http2Session}
 };
 // use tokio::net::TcpStream;
 
 use std::{
-    env, path::Path, sync::Arc, time::Instant
+    collections::HashMap, env, net::SocketAddr, path::Path, sync::{atomic::{AtomicU32, AtomicUsize, Ordering}, Arc, Mutex, RwLock}, time::{Duration, Instant}
 };
 
-use crate::{middleware::MiddlewareData, mime_map::mime_map, structs::SharedData};
+use crate::{clientauth::ClientCert, compressible::compressible_map, config::{Args, Config}, http3::H3Socket, listen::{Conn, Listener}, middleware::MiddlewareData, mime_map::mime_map, shutdown::Shutdown, structs::SharedData, vhost::{Certs, HostTable}};
 
-use rustls::{Certificate, PrivateKey, ServerConfig};
-use rustls_pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
-use std::{fs::File, io::BufReader};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::{io::DuplexStream, net::TcpStream, sync::mpsc, task::JoinHandle};
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio_rustls::{/*server::TlsStream,*/ server::TlsStream, TlsAcceptor};
 
 // impl Stream for tokio_rustls::TlsStream<TcpStream>{}
 
-const SETTINGS:Http2FrameSettings=Http2FrameSettings{
-    header_table_size: Some(16777215),
-    enable_push: None,
-    max_concurrent_streams: None,
-    initial_window_size: Some(65535),
-    max_frame_size: Some(65535),
-    max_header_list_size: None,
-};
-
-fn load_certs(path: &str) -> std::io::Result<Vec<Certificate>> {
-    let f = File::open(path)?;
-    let mut reader = BufReader::new(f);
-    let certs = certs(&mut reader)?;
-    Ok(certs.into_iter().map(Certificate).collect())
-}
-
-fn load_private_key(path: &str) -> std::io::Result<PrivateKey> {
-    let f = File::open(path)?;
-    let mut reader = BufReader::new(f);
-
-    // pkcs8
-    if let Ok(mut keys) = pkcs8_private_keys(&mut reader) {
-        if !keys.is_empty() {
-            return Ok(PrivateKey(keys.remove(0)));
-        }
-    }
-
-    // rsa
-    let f = File::open(path)?;
-    let mut reader = BufReader::new(f);
-    if let Ok(mut keys) = rsa_private_keys(&mut reader) {
-        if !keys.is_empty() {
-            return Ok(PrivateKey(keys.remove(0)));
-        }
-    }
-
-    Err(std::io::Error::new(std::io::ErrorKind::Unsupported,format!("no private keys found in {}", path)))
-}
-
-fn load_key_cert(key_path:&str,cert_path:&str)->Option<(PrivateKey,Vec<Certificate>)>{
-    let key=match load_private_key(key_path){
-        Ok(k)=>k,
-        Err(e)=>{
-            eprintln!("reading private key failed {e:?}");
-            return None;
-        }
-    };
-    let certs=match load_certs(cert_path){
-        Ok(cs)=>cs,
-        Err(e)=>{
-            eprintln!("reading certificates failed {e:?}");
-            return None;
-        }
-    };
-    println!("successfully read private key and certificates");
-    Some((key,certs))
+fn main()->std::io::Result<()> {
+    // before the runtime starts any threads
+    listen::take_systemd_env();
+    run()
 }
 
 #[tokio::main]
-async fn main()->std::io::Result<()> {
+async fn run()->std::io::Result<()> {
     let start=Instant::now();
 
     match dotenvy::from_path(Path::new(".env")){
         Err(e)=>eprintln!("WARNING: couldnt load .env file {:?}",e),
         Ok(_)=>(),
     };
-    let mut serve_dir: String = env::var("SERVE_DIR").unwrap_or("./public".to_string());
-    let mut address = env::var("ADDRESS").unwrap_or("0.0.0.0:8000".to_string());
-    
-    let mut cert_path = env::var("CERT_PATH").unwrap_or("localhost.crt".to_string());
-    let mut key_path = env::var("KEY_PATH").unwrap_or("localhost.key".to_string());
-
-    let h2_enabled = env::var("ALLOW_HTTP2").map(|v|{
-        match v.to_lowercase().as_str() {
-            "yes" | "y" | "1" | "true" => true,
-
-            _=>false,
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).is_some_and(|a|a=="gen-cert"){
+        if let Err(e)=devcert::gen_cert(&args[2..]){
+            eprintln!("\x1b[31m{e}\x1b[0m");
+            std::process::exit(1);
         }
-    }).unwrap_or(true);
-    let h2_priority = if h2_enabled==false{false}else{
-            env::var("H2_FIRST").map(|v|{
-            match v.to_lowercase().as_str() {
-                "yes" | "y" | "1" | "true" => true,
-
-                _=>false,
-            }
-        }).unwrap_or(true)
+        std::process::exit(0);
+    }
+    let args=match Args::parse(&args){
+        Ok(a)=>a,
+        Err(e)=>{
+            eprintln!("\x1b[31m{e}\x1b[0m\nsee {} --help",args[0]);
+            std::process::exit(2);
+        },
     };
 
-    let args: Vec<String> = env::args().collect();
-
-    if args.len()==2 && (args[1]=="-h"||args[1]=="--help"){
+    if args.help{
+        let name=env::args().next().unwrap_or("static_serve".to_owned());
         println!("\t");
-        println!("\x1b[32musage\x1b[0m: {} address directory tls_key_path tls_cert_path",args[0]);
-        println!("\x1b[33mexample\x1b[0m: {} 0.0.0.0:2000 ./files ./key.pem ./cert.pem",args[0]);
-        println!("\x1b[34mdefault\x1b[0m: {} 0.0.0.0:8000 ./public ./localhost.key ./localhost.crt",args[0]);
-        println!("\x1b[35mthese parameters can also be passed down through environmental variable ADDRESS, SERVE_DIR, KEY_PATH, and CERT_PATH\x1b[0m");
-        println!("env ALLOW_HTTP2: decides wether http2 is used at all. true by default");
-        println!("env H2_FIRST: indicates which protocol comes first in alpn negotiation. false by default");
+        println!("\x1b[32musage\x1b[0m: {} [--config static-serve.toml] [flags] [address] [directory] [key] [cert]",name);
+        println!("\x1b[33mexample\x1b[0m: {} --address 0.0.0.0:2000 --serve-dir ./files --key ./key.pem --cert ./cert.pem",name);
+        println!("\x1b[34mdefault\x1b[0m: {} --address 0.0.0.0:8000 --serve-dir ./public --key ./localhost.key --cert ./localhost.crt",name);
+        println!("  -c, --config PATH        toml config file. {} is used when it exists",config::DEFAULT_CONFIG_PATH);
+        println!("      --check-config       validate the configuration and exit");
+        println!("  -a, --address ADDR       address to listen on");
+        println!("  -l, --listen SPEC        listen on an address, unix:PATH or systemd. repeatable, replaces --address");
+        println!("      --redirect-from ADDR plain http address that redirects everything to https, like 0.0.0.0:80");
+        println!("  -d, --serve-dir DIR      directory to serve");
+        println!("      --key PATH, --cert PATH  tls private key and certificate chain, or a .p12 bundle as --key");
+        println!("      --tls, --no-tls      try to use tls at all");
+        println!("      --dev-tls[=BOOL]     serve a generated self signed certificate for localhost and the vhosts");
+        println!("      --client-auth MODE   ask for client certificates: none, optional or required");
+        println!("      --client-ca PATH     pem bundle client certificates are verified against");
+        println!("      --http2, --no-http2  decides wether http2 is used at all");
+        println!("      --h2-first[=BOOL]    offer h2 before http/1.1 in alpn negotiation");
+        println!("      --http3, --no-http3  serve http/3 over quic on the same port, needs tls");
+        println!("      --autoindex[=BOOL]   list directories that have no index file instead of answering 409");
+        println!("      --site-files[=BOOL]  apply _redirects and _headers from the serve directory");
+        println!("      --strong-etag[=BOOL] use content hashes instead of mtime and size as etags");
+        println!("      --compress-min-size N  files smaller than this many bytes are never compressed");
+        println!("      --keep-alive[=BOOL]  reuse http/1.1 connections for several requests");
+        println!("      --header-preset NAME secure adds HSTS, nosniff, CSP and Referrer-Policy to responses, none doesnt");
+        println!("      --drain-timeout SECS seconds to wait for open connections after SIGINT/SIGTERM");
+        println!("       {} gen-cert [--key PATH] [--cert PATH] [--force] [NAME...]  write a self signed key and certificate",name);
+        println!("\x1b[35mprecedence is flags > env > config file > defaults\x1b[0m");
+        println!("env: CONFIG, ADDRESS, LISTEN, SERVE_DIR, KEY_PATH, CERT_PATH, USE_TLS, ALLOW_HTTP2, H2_FIRST, ALLOW_HTTP3, STRONG_ETAG, AUTOINDEX,");
+        println!("     COMPRESS_MIN_SIZE, COMPRESS_SKIP, COMPRESS_ALLOW (comma separated mime types or type/*), DRAIN_TIMEOUT,");
+        println!("     KEEP_ALIVE, KEEP_ALIVE_TIMEOUT, MAX_REQUESTS, TLS_RELOAD_INTERVAL, PKCS12_PASSWORD, HEADER_PRESET,");
+        println!("     DEV_TLS, DEV_TLS_NAMES, DEV_TLS_PERSIST, CLIENT_AUTH, CLIENT_CA_PATH, SITE_FILES,");
+        println!("     REDIRECT_ADDRESS, ACME, ACME_DIRECTORY, ACME_DOMAINS, ACME_CONTACT, ACME_STATE_DIR, ACME_CA_PATH");
         println!("\x1b[36m.env file for parameters supported\x1b[0m");
         println!("\t");
         std::process::exit(0);
         // return Ok(())
     }
-    if args.len() > 1 {
-        let host_str = args[1].clone();
-        address=host_str;
-    } if args.len() > 2 {
-        serve_dir = args[2].clone();
-    } if args.len() > 4 {
-        key_path = args[3].clone();
-        cert_path = args[4].clone();
+
+    let config=match Config::load(&args){
+        Ok(c)=>c,
+        Err(e)=>{
+            eprintln!("\x1b[31mcouldnt load config\x1b[0m {e}");
+            std::process::exit(1);
+        },
     };
 
-    let key_cert=load_key_cert(&key_path, &cert_path);
+    let problems=config.validate();
+    for p in &problems{ eprintln!("\x1b[31m{p}\x1b[0m") }
+    if !problems.is_empty() { std::process::exit(1) }
+    if args.check_config{
+        println!("\x1b[32mconfig ok\x1b[0m\n{:#?}",config);
+        std::process::exit(0);
+    }
+
+    let serve_dir=config.serve_dir.clone();
+
+    let listeners=listen::bind_all(&config)?;
+    let certs=if listeners.iter().any(|l|l.tls) { tls::load_cert_table(&config) } else { Certs::default() };
 
     println!(
         "Parameters of the server are\n\x1b[32maddress = {}\n\x1b[34mdirectory = {}\x1b[0m\n\x1b[33muse tls = {}\x1b[0m",
-        address, serve_dir, key_cert.is_some(),
+        listeners.iter().map(|l|l.name.as_str()).collect::<Vec<_>>().join(", "), serve_dir, !certs.is_empty(),
     );
+    for vhost in &config.vhosts{
+        println!("\x1b[34mvhost {} = {}\x1b[0m",vhost.hosts.join(", "),vhost.serve_dir);
+    }
 
-    let tls_config=if let Some((key,certs))=key_cert.clone(){
-        let sco=ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(certs,key).ok();
-        match sco{
-            Some(mut sc)=>{
-                sc.alpn_protocols=vec![b"http/1.1".to_vec()];
-                if h2_enabled&&h2_priority { sc.alpn_protocols=vec![b"h2".to_vec(),b"http/1.1".to_vec()] };
-                if h2_enabled { sc.alpn_protocols.push(b"h2".to_vec()) };
+    let challenges=acme::Challenges::default();
+    let tls_config=if !certs.is_empty(){
+        match tls::server_config(&certs, &config, &challenges){
+            Ok(sc)=>{
                 // sc.alpn_protocols=vec![b"h2".to_vec(),b"http/1.1".to_vec()];
-                let acc=TlsAcceptor::from(Arc::new(sc));
-                Some(acc)
+                Some(Arc::new(RwLock::new(tls::Acceptors::new(sc))))
             },
-            None=>None,
+            Err(e)=>{ eprintln!("{e}"); None },
         }
     }else{None};
 
-    if key_cert.is_some()&&tls_config.is_some(){ println!("succesfully loaded tls config") }
-    else if key_cert.is_some()&&tls_config.is_none(){ eprintln!("couldnt load tls. using plain tcp") }
+    if !certs.is_empty()&&tls_config.is_some(){ println!("succesfully loaded tls config") }
+    else if !certs.is_empty()&&tls_config.is_none(){ eprintln!("couldnt load tls. using plain tcp") }
+    else if config.tls.enabled{ eprintln!("\x1b[33mno usable certificate, using plain tcp. create one with `gen-cert` or serve a temporary one with --dev-tls\x1b[0m") }
+
+    let redirect_server=match &config.redirect.address{
+        Some(a)=>Some(tokio::net::TcpListener::bind(a).await?),
+        None=>None,
+    };
+
+    // the first tcp listener with tls, quic shares its port number just on udp
+    let https_addr=tls_config.as_ref().and_then(|_|listeners.iter().filter(|l|l.tls).find_map(Listener::tcp_addr));
+    let h3_endpoint=match https_addr{
+        Some(addr) if config.http3.enabled=>match http3::endpoint(addr, &certs, &config.client_auth){
+            Ok(e)=>Some(e),
+            Err(e)=>{ eprintln!("couldnt start http/3 {e:?}"); None },
+        },
+        _=>None,
+    };
+    let alt_svc=h3_endpoint.as_ref().map(|_|format!("h3=\":{}\"; ma={}",https_addr.map_or(0,|a|a.port()),config.http3.alt_svc_max_age));
 
+    let mime=mime_map();
+    let compressible=compressible_map(&mime, &config.compression.skip, &config.compression.allow);
+    let mut vhosts=HostTable::new();
+    for vhost in &config.vhosts{
+        for host in &vhost.hosts{ vhosts.insert(host, vhost.serve_dir.clone()) }
+    }
     let shared=Arc::new(SharedData{
-        mime: mime_map(), 
+        mime, 
         serve_dir,
+        vhosts,
         tls_acceptor: tls_config,
+        client_rules: config.client_auth.rules.clone(),
+        acme: challenges.clone(),
+        etag_cache: if config.strong_etag { Some(Arc::new(Mutex::new(HashMap::new()))) } else { None },
+        autoindex: config.autoindex,
+        site_files: config.site_files.then(site::SiteFiles::default),
+        compress_min_size: config.compression.min_size,
+        compressible,
+        headers: headers::HeaderRules::new(&config),
+        middleware: config.middleware.enabled.clone(),
+        http2: config.http2.clone(),
+        http1: config.http1.clone(),
+        alt_svc,
+        shutdown: Shutdown::new(),
     });
-    let middleware_data_tls=Arc::new(MiddlewareData::<TlsStream<TcpStream>>{
-        ..MiddlewareData::empty()
-    });
-    let middleware_data_tcp=Arc::new(MiddlewareData::<TcpStream>{
-        ..MiddlewareData::empty()
-    });
+    let middlewares=Middlewares::new();
     
     // let listener = {
     //     let shared=Arc::clone(&shared);
@@ -192,87 +211,250 @@ async fn main()->std::io::Result<()> {
     //     }
     // };
 
-    ctrlc::set_handler(move||{
-        println!("\x1b[31mSIG_INT received\x1b[0m\n\x1b[36mprocess exit after {}s\x1b[0m",&start.elapsed().as_millis()/1000);
-        std::process::exit(0);
-    }).expect("couldnt set ctrl+c handler");
-
-    println!("http://{}/",&address);
+    for listener in &listeners{
+        match listener.tcp_addr(){
+            Some(addr)=>println!("{}://{addr}/",if listener.tls&&shared.tls_acceptor.is_some() {"https"} else {"http"}),
+            None=>println!("listening on {}",listener.name),
+        }
+    }
     // listener::http_listener(&address, listener).await.unwrap();
-    let server = TcpListener::bind(&address).await?;
     // let h2_enabled=h2_enabled.clone();
     
-    println!("http2 settings are {:?}",SETTINGS);
+    println!("http2 settings are {:?}",shared.http2.settings());
+    if let Some(acceptor)=&shared.tls_acceptor{
+        tokio::spawn(tls::watch(config.clone(), Arc::clone(acceptor), h3_endpoint.clone(), challenges.clone(), shared.shutdown.subscribe()));
+        if config.acme.enabled{
+            tokio::spawn(acme::run(config.clone(), challenges.clone(), Arc::clone(acceptor), h3_endpoint.clone(), shared.shutdown.subscribe()));
+        }
+    }
+    match redirect_server{
+        Some(redirect_server) if shared.tls_acceptor.is_some()=>{
+            let https_port=config.redirect.https_port.or(https_addr.map(|a|a.port())).unwrap_or(443);
+            println!("\x1b[35mredirecting http://{} to https on port {https_port}\x1b[0m",config.redirect.address.as_deref().unwrap_or_default());
+            tokio::spawn(redirect::listen(Arc::clone(&shared), redirect_server, config.redirect.status, https_port));
+        },
+        Some(_)=>eprintln!("\x1b[33mtls isnt available, not redirecting {}\x1b[0m",config.redirect.address.as_deref().unwrap_or_default()),
+        None=>(),
+    }
+    if let Some(endpoint)=h3_endpoint{
+        println!("\x1b[35mhttp/3 on udp {}\x1b[0m",https_addr.map_or(String::new(),|a|a.to_string()));
+        let middleware_data_h3=Arc::new(MiddlewareData::<DuplexStream>::secure());
+        tokio::spawn(h3_listener(Arc::clone(&shared), middleware_data_h3, endpoint));
+    }
     // println!("{:?}",SETTINGS.to_buff());
 
+    for listener in listeners{
+        tokio::spawn(accept_loop(Arc::clone(&shared), middlewares.clone(), listener));
+    }
+    shutdown::signal().await;
+
+    // the accept loops stop on this, then whatever is in flight gets to finish
+    shared.shutdown.trigger();
+    println!("\x1b[33mdraining {} connections for up to {}s\x1b[0m",shared.shutdown.active(),config.shutdown.drain_timeout);
+    middlewares.close_websockets().await;
+    if !shared.shutdown.drain(Duration::from_secs(config.shutdown.drain_timeout)).await{
+        eprintln!("\x1b[31mdrain timeout reached, {} connections cut off\x1b[0m",shared.shutdown.active());
+    }
+
+    println!("\x1b[36mprocess exit after {}s\x1b[0m",&start.elapsed().as_millis()/1000);
+    Ok(())
+}
+
+// peer address for unix socket connections, they dont have one
+const UNIX_PEER: SocketAddr=SocketAddr::V4(std::net::SocketAddrV4::new(std::net::Ipv4Addr::UNSPECIFIED, 0));
+
+/// middleware data per stream type, every listener of a kind shares one so websockets can be closed on shutdown
+#[derive(Clone)]
+struct Middlewares{
+    tls_tcp: Arc<MiddlewareData<TlsStream<TcpStream>>>,
+    tcp: Arc<MiddlewareData<TcpStream>>,
+    #[cfg(unix)]
+    tls_unix: Arc<MiddlewareData<TlsStream<UnixStream>>>,
+    #[cfg(unix)]
+    unix: Arc<MiddlewareData<UnixStream>>,
+}
+
+impl Middlewares{
+    fn new()->Self{
+        Middlewares{
+            tls_tcp: Arc::new(MiddlewareData::secure()),
+            tcp: Arc::new(MiddlewareData::empty()),
+            #[cfg(unix)]
+            tls_unix: Arc::new(MiddlewareData::secure()),
+            #[cfg(unix)]
+            unix: Arc::new(MiddlewareData::empty()),
+        }
+    }
+
+    async fn close_websockets(&self){
+        shutdown::close_websockets(&self.tls_tcp.clients.lock().await).await;
+        shutdown::close_websockets(&self.tcp.clients.lock().await).await;
+        #[cfg(unix)]
+        {
+            shutdown::close_websockets(&self.tls_unix.clients.lock().await).await;
+            shutdown::close_websockets(&self.unix.clients.lock().await).await;
+        }
+    }
+}
+
+/// accepts connections on one listener until shutdown
+async fn accept_loop(shared: Arc<SharedData>, middlewares: Middlewares, listener: Listener){
+    let mut shutdown=shared.shutdown.subscribe();
     loop{
-        let h2_enabled=h2_enabled.clone();
-        let (socket, addr) = server.accept().await?;
+        let conn=tokio::select!{
+            r=listener.accept()=>r,
+            _=shutdown.wait_for(|s|*s)=>break,
+        };
+        let conn=match conn{
+            Ok(c)=>c,
+            Err(e)=>{
+                // usually out of file descriptors, give others a moment to close
+                eprintln!("accepting on {} failed {e:?}",listener.name);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue
+            },
+        };
         let shared=Arc::clone(&shared);
-        //let listener=listener.clone();
-        if let Some(acc)=&shared.tls_acceptor{
-            let acceptor = acc.clone();
-            let middleware_data_tls=Arc::clone(&middleware_data_tls);
-            tokio::spawn(async move {
-                match acceptor.accept(socket).await{
-                    Ok(tls_sock)=>{
-                        // let tls_sock: tokio_rustls::server::TlsStream<tokio::net::TcpStream>=tls_sock;
-                        let alpn = tls_sock.get_ref().1.alpn_protocol().map(|v| String::from_utf8_lossy(v).to_string());
-                        match alpn.as_deref(){
-                            Some("h2")=>{
-                                println!("\x1b[35mexplicitly use http/2\x1b[0m");
-                                let h2=Http2Session::new(tls_sock, addr, Http2FrameSettings::default());
-                                let h2=Arc::new(h2);
-                                // h2_wrapper(shared, middleware_data_tls, h2).await.unwrap();
-                                match h2_wrapper(shared, middleware_data_tls, h2).await{
-                                    Ok(_)=>(),
-                                    Err(e)=>{
-                                        eprintln!("h2 handler error {e:?}");
-                                        dbg!(e);
-                                    },
-                                }
-                            },
-                            Some("http/1.1")=>{
-                                println!("\x1b[35mexplicitly use http/1.1\x1b[0m");
-                                let mut hand=Http1Socket::new(tls_sock,addr);
-                                let _=hand.read_client().await;
-                                listener(shared, middleware_data_tls, hand).await;
-                            },
-                            a=>{
-                                println!("\x1b[35munknown alpn {a:?}\x1b[0m");
-                                let hand=Http1Socket::new(tls_sock,addr);
-                                match h2c_or_plain(shared, middleware_data_tls, hand).await{
-                                    Ok(_)=>(),
-                                    Err(e)=>eprintln!("could not complete h2c detection {e:?}"),
-                                };
-                            }
-                        };
-                    },
-                    Err(err)=>{
-                        eprintln!("tls handshake failed {:?}",err);
-                    }
-                }
-            });
-        } else if shared.tls_acceptor.is_none(){
+        // a listener that wants tls falls back to plain tcp when no certificate loaded
+        let acceptor=if listener.tls { shared.acceptor(listener.http2) } else { None };
+        let http2=listener.http2;
+        match conn{
+            Conn::Tcp(socket,addr)=>{
+                let (tls_data,data)=(Arc::clone(&middlewares.tls_tcp),Arc::clone(&middlewares.tcp));
+                tokio::spawn(async move {
+                    let _guard=shared.shutdown.track();
+                    let preface=acceptor.is_none() && http2 && h2_prior_knowledge(&shared, &socket).await;
+                    connection(shared, tls_data, data, acceptor, http2, preface, socket, addr).await;
+                });
+            },
+            #[cfg(unix)]
+            Conn::Unix(socket)=>{
+                let (tls_data,data)=(Arc::clone(&middlewares.tls_unix),Arc::clone(&middlewares.unix));
+                tokio::spawn(async move {
+                    let _guard=shared.shutdown.track();
+                    // unix streams cant be peeked, prior knowledge h2 isnt detected on them
+                    connection(shared, tls_data, data, acceptor, http2, false, socket, UNIX_PEER).await;
+                });
+            },
+        }
+    }
+    println!("\x1b[33mstopped listening on {}\x1b[0m",listener.name);
+}
+
+/// serves one accepted connection, over tls when there is an `acceptor`.
+/// `preface` means a plain connection already sent the http/2 preface
+#[allow(clippy::too_many_arguments)]
+async fn connection<S:Stream+'static>(shared: Arc<SharedData>, middleware_data_tls: Arc<MiddlewareData<TlsStream<S>>>, middleware_data: Arc<MiddlewareData<S>>, acceptor: Option<TlsAcceptor>, http2: bool, preface: bool, socket: S, addr: SocketAddr){
+    let Some(acceptor)=acceptor else {
+        if preface{
+            println!("\x1b[35mhttp/2 with prior knowledge\x1b[0m");
+            let h2=Arc::new(Http2Session::new(socket, addr, shared.http2.settings()));
+            if let Err(e)=h2_wrapper(shared, middleware_data, h2).await{
+                eprintln!("h2 handler error {e:?}");
+            }
+        } else if http2{
             let hand=Http1Socket::new(socket,addr);
-            let middleware_data_tcp=Arc::clone(&middleware_data_tcp);
-            tokio::spawn(async move {
-                if h2_enabled{
-                    match h2c_or_plain(shared, middleware_data_tcp, hand).await{
+            match h2c_or_plain(shared, middleware_data, hand).await{
+                Ok(_)=>(),
+                Err(e)=>eprintln!("could not complete h2c detection {e:?}"),
+            };
+        } else {
+            let hand=Http1Socket::new(socket,addr);
+            http1_listener(shared, middleware_data, hand).await;
+        }
+        return
+    };
+
+    match acceptor.accept(socket).await{
+        Ok(tls_sock)=>{
+            // let tls_sock: tokio_rustls::server::TlsStream<tokio::net::TcpStream>=tls_sock;
+            let alpn = tls_sock.get_ref().1.alpn_protocol().map(|v| String::from_utf8_lossy(v).to_string());
+            let peer=tls_sock.get_ref().1.peer_certificates().and_then(ClientCert::from_chain);
+            let server_name=tls_sock.get_ref().1.sni_hostname().map(|n|n.to_owned());
+            if let Some(peer)=&peer{ println!("\x1b[35mclient certificate {}\x1b[0m",peer.subject) }
+            let middleware_data_tls=Arc::new(middleware_data_tls.with_session(peer, server_name));
+            match alpn.as_deref(){
+                // the handshake itself was the tls-alpn-01 validation
+                Some("acme-tls/1")=>println!("\x1b[33macme tls-alpn-01 validation\x1b[0m"),
+                Some("h2")=>{
+                    println!("\x1b[35mexplicitly use http/2\x1b[0m");
+                    let h2=Http2Session::new(tls_sock, addr, shared.http2.settings());
+                    let h2=Arc::new(h2);
+                    // h2_wrapper(shared, middleware_data_tls, h2).await.unwrap();
+                    match h2_wrapper(shared, middleware_data_tls, h2).await{
+                        Ok(_)=>(),
+                        Err(e)=>{
+                            eprintln!("h2 handler error {e:?}");
+                            dbg!(e);
+                        },
+                    }
+                },
+                Some("http/1.1")=>{
+                    println!("\x1b[35mexplicitly use http/1.1\x1b[0m");
+                    let mut hand=Http1Socket::new(tls_sock,addr);
+                    let _=hand.read_client().await;
+                    http1_listener(shared, middleware_data_tls, hand).await;
+                },
+                a if http2=>{
+                    println!("\x1b[35munknown alpn {a:?}\x1b[0m");
+                    let hand=Http1Socket::new(tls_sock,addr);
+                    match h2c_or_plain(shared, middleware_data_tls, hand).await{
                         Ok(_)=>(),
                         Err(e)=>eprintln!("could not complete h2c detection {e:?}"),
                     };
-                } else {
-                    listener(shared, middleware_data_tcp, hand).await;
-                }
-            });
+                },
+                a=>{
+                    println!("\x1b[35munknown alpn {a:?}\x1b[0m");
+                    let hand=Http1Socket::new(tls_sock,addr);
+                    http1_listener(shared, middleware_data_tls, hand).await;
+                },
+            };
+        },
+        Err(err)=>{
+            eprintln!("tls handshake failed {:?}",err);
         }
     }
-    
+}
 
-    // println!("process exit after {}s",&start.elapsed().as_millis()/1000);
+// RST_STREAM error code telling the client the stream was never processed
+const REFUSED_STREAM: u32=0x7;
+
+/// sends GOAWAY once shutdown starts. the returned task has to be aborted when the session ends
+fn goaway_on_shutdown<S:Stream+'static>(shared: &SharedData, h2: &Arc<Http2Session<S>>, last_stream: &Arc<AtomicU32>)->JoinHandle<()>{
+    let mut shutdown=shared.shutdown.subscribe();
+    let h2=Arc::downgrade(h2);
+    let last_stream=Arc::clone(last_stream);
+    tokio::spawn(async move {
+        if shutdown.wait_for(|s|*s).await.is_err(){ return }
+        let Some(h2)=h2.upgrade() else { return };
+        let last=last_stream.load(Ordering::SeqCst);
+        println!("\x1b[33msending goaway, last stream {last}\x1b[0m");
+        if let Err(e)=h2.send_goaway(last, 0).await{ eprintln!("couldnt send goaway {e:?}") }
+        let _=h2.flush().await;
+    })
+}
 
-    // Ok(())
+// every http/2 connection starts with this, http/1.1 requests can never begin with "PRI "
+const H2_PREFACE: &[u8]=b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// peeks at the first bytes of a plain connection for the http/2 preface, without consuming them.
+/// gives up as soon as the bytes cant be the preface, so http/1.1 clients arent held up
+async fn h2_prior_knowledge(shared: &SharedData, socket: &TcpStream)->bool{
+    let mut buf=[0u8; H2_PREFACE.len()];
+    let peek=async{
+        loop{
+            let n=socket.peek(&mut buf).await?;
+            if n==0 || buf[..n]!=H2_PREFACE[..n] { return Ok::<bool,std::io::Error>(false) }
+            if n==H2_PREFACE.len() { return Ok(true) }
+            // only part of the preface arrived yet, peek returns right away so dont spin on it
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    };
+    match tokio::time::timeout(Duration::from_secs(shared.http1.keep_alive_timeout.max(1)), peek).await{
+        Ok(Ok(v))=>v,
+        Ok(Err(e))=>{ eprintln!("couldnt peek connection {e:?}"); false },
+        Err(_)=>false,
+    }
 }
 
 async fn h2c_or_plain<S:Stream+'static>(shared: Arc<SharedData>, middleware_data: Arc<MiddlewareData<S>>, mut hand: Http1Socket<S>)->HttpResult<()>{
@@ -281,35 +463,10 @@ async fn h2c_or_plain<S:Stream+'static>(shared: Arc<SharedData>, middleware_data
             if client.headers.get("upgrade").map_or(false, |u|u[0]=="h2c"){
                 let h2=hand.h2c().await?;
                 let h2=Arc::new(h2);
-                h2.init().await?;
-                let mut f=h2.incoming_frames().await?;
-                h2.send_settings(SETTINGS).await?;
-                h2.flush().await?;
+                let f=h2_start(&shared, &h2).await?;
 
-                let mut new=h2.handle_frames(f.clone()).await?;
-
-                let mut hand=Http2Handler::new(1, Arc::clone(&h2));
-                let shared2=Arc::clone(&shared);
-                let middleware_data2=Arc::clone(&middleware_data);
-                tokio::spawn(async move {
-                    let _=hand.read_client().await;
-                    listener(Arc::clone(&shared2), Arc::clone(&middleware_data2), hand).await;
-                });
-                f.clear();
-                loop{
-                    for stream_id in new{
-                        let mut hand=Http2Handler::new(stream_id, Arc::clone(&h2));
-                        let shared=Arc::clone(&shared);
-                        let middleware_data=Arc::clone(&middleware_data);
-                        tokio::spawn(async move {
-                            let _=hand.read_client().await;
-                            listener(Arc::clone(&shared), Arc::clone(&middleware_data), hand).await;
-                        });
-                    };
-                    new=h2.handle_frames(f).await?;
-                    f=h2.incoming_frames().await.expect("error reading frames");
-                    if f.len()==0{ println!("\x1b[31mhttp2 connection closed\x1b[0m"); return Ok(()) };
-                }
+                // the upgraded request becomes stream 1
+                return h2_frame_loop(shared, middleware_data, h2, f, vec![1]).await;
             }
         },
         Err(e)=>{
@@ -317,64 +474,64 @@ async fn h2c_or_plain<S:Stream+'static>(shared: Arc<SharedData>, middleware_data
             println!("proceed as normal (http1.1)");
         }
     };
-    listener(shared, middleware_data, hand).await;
+    http1_listener(shared, middleware_data, hand).await;
     Ok(())
 }
 
 async fn h2_wrapper<S:Stream+'static>(shared: Arc<SharedData>, middleware_data: Arc<MiddlewareData<S>>, h2: Arc<Http2Session<S>>)->HttpResult<()>{
+    let f=h2_start(&shared, &h2).await?;
+    h2_frame_loop(shared, middleware_data, h2, f, vec![]).await
+}
+
+// initial flow control window of a connection before any WINDOW_UPDATE
+const DEFAULT_WINDOW_SIZE: u32=65535;
+// hpack table size until SETTINGS say otherwise
+const DEFAULT_HEADER_TABLE_SIZE: u32=4096;
+
+/// reads the preface and answers with our SETTINGS, returns the frames read so far.
+/// the decoder table has to match the header table size we announce
+async fn h2_start<S:Stream+'static>(shared: &SharedData, h2: &Http2Session<S>)->HttpResult<Vec<Http2Frame>>{
     h2.init().await?;
-    let mut f=h2.incoming_frames().await?;
-    h2.send_settings(SETTINGS).await?;
-    
+    let f=h2.incoming_frames().await?;
+    h2.send_settings(shared.http2.settings()).await?;
+
     {
         let mut hpackd=h2.hpackd.lock().unwrap();
-        hpackd.set_max_table_size(16777215);
+        hpackd.set_max_table_size(shared.http2.header_table_size.unwrap_or(DEFAULT_HEADER_TABLE_SIZE) as usize);
         drop(hpackd);
     }
- 
-    loop{
-        if f.len()==0{ println!("\x1b[31mhttp2 connection closed\x1b[0m"); break };
+    if let Some(size)=shared.http2.connection_window_size{
+        if size>DEFAULT_WINDOW_SIZE{
+            h2.send_window_update(0, size-DEFAULT_WINDOW_SIZE).await?;
+        }
+    }
+    h2.flush().await?;
+    Ok(f)
+}
+
+/// reads and handles frames for the whole connection. new streams are handed to
+/// `dispatch_streams` so a slow handler never holds up frames of other streams
+async fn h2_frame_loop<S:Stream+'static>(shared: Arc<SharedData>, middleware_data: Arc<MiddlewareData<S>>, h2: Arc<Http2Session<S>>, mut f: Vec<Http2Frame>, opened: Vec<u32>)->HttpResult<()>{
+    let last_stream=Arc::new(AtomicU32::new(0));
+    let goaway=goaway_on_shutdown(&shared, &h2, &last_stream);
+    let (new_streams, rx)=mpsc::unbounded_channel();
+    tokio::spawn(dispatch_streams(shared, middleware_data, Arc::clone(&h2), rx, last_stream));
+    for stream_id in opened{ let _=new_streams.send(stream_id); }
+
+    let res=loop{
+        if f.len()==0{ println!("\x1b[31mhttp2 connection closed\x1b[0m"); break Ok(()) };
         for frame in &f{
             // if frame.flags.acknowledge { continue }
             println!("type = \x1b[34m{:?}\x1b[0m",frame.ftype);
             println!("flags = {:?}",frame.flags);
             // println!("frame = {:?}",frame);
-            match frame.ftype{
-                Http2FrameType::Headers=>{
-                    // let dec=h2.hpack_decode(frame.get_payload()).await.unwrap();
-                    // for (h,v) in dec{
-                    //     println!("{}: {}",String::from_utf8_lossy(&h),String::from_utf8_lossy(&v));
-                    // }
-                },
-                _=>()
-            }
         };
-        let new=h2.handle_frames(f.clone()).await?;
+        let new=match h2.handle_frames(f).await{
+            Ok(v)=>v,
+            Err(e)=>break Err(e),
+        };
         for stream_id in new{
-            println!("new stream opened {stream_id}");
-            let mut hand: Http2Handler<S>=Http2Handler::new(stream_id, Arc::clone(&h2));
-            let shared=Arc::clone(&shared);
-            // let h2=Arc::clone(&h2);
-            let _=hand.read_client().await;
-            let shared = Arc::clone(&shared);
-            let middleware_data=Arc::clone(&middleware_data);
-            tokio::spawn(async move {
-                listener(shared, Arc::clone(&middleware_data), hand).await;
-            });
-
-            // tokio::spawn(async move {
-            //     // loop{
-            //     //     let streams=h2.streams.lock().await;
-            //     //     let stream=streams.get(&stream_id).ok_or(HttpError::StreamDoesntExist).unwrap();
-            //     //     if stream.end_headers { break };
-            //     //     drop(streams);
-            //     //     let f=h2.incoming_frames().await.unwrap();
-            //     //     if f.is_empty(){break}
-            //     //     h2.handle_frames(f).await.unwrap();
-            //     // }
-            //     let _=hand.read_client().await;
-            //     listener(Arc::clone(&shared), hand).await;
-            // });
+            let _=new_streams.send(stream_id);
         };
         f=match h2.incoming_frames().await{
             Ok(v)=>v,
@@ -384,22 +541,196 @@ async fn h2_wrapper<S:Stream+'static>(shared: Arc<SharedData>, middleware_data:
                 vec![]
             },
         };
+    };
+    goaway.abort();
+    res
+}
+
+/// spawns a handler task per stream, streams past `max_concurrent_streams`
+/// or opened during shutdown are reset with REFUSED_STREAM so the client can retry them
+async fn dispatch_streams<S:Stream+'static>(shared: Arc<SharedData>, middleware_data: Arc<MiddlewareData<S>>, h2: Arc<Http2Session<S>>, mut new_streams: mpsc::UnboundedReceiver<u32>, last_stream: Arc<AtomicU32>){
+    let open=Arc::new(AtomicUsize::new(0));
+    while let Some(stream_id)=new_streams.recv().await{
+        let refuse=if shared.shutdown.is_shutting_down(){
+            println!("refusing stream {stream_id} during shutdown");
+            true
+        }else if shared.http2.max_concurrent_streams.is_some_and(|max|open.load(Ordering::SeqCst)>=max as usize){
+            println!("\x1b[33mrefusing stream {stream_id}, {} streams open\x1b[0m",open.load(Ordering::SeqCst));
+            true
+        }else{ false };
+        if refuse{
+            if let Err(e)=h2.send_rst_stream(stream_id, REFUSED_STREAM).await{ eprintln!("couldnt reset stream {stream_id} {e:?}") }
+            let _=h2.flush().await;
+            continue
+        }
+
+        last_stream.fetch_max(stream_id, Ordering::SeqCst);
+        println!("new stream opened {stream_id}");
+        open.fetch_add(1, Ordering::SeqCst);
+        let slot=StreamSlot(Arc::clone(&open));
+        let mut hand: Http2Handler<S>=Http2Handler::new(stream_id, Arc::clone(&h2));
+        let shared=Arc::clone(&shared);
+        let middleware_data=Arc::clone(&middleware_data);
+        tokio::spawn(async move {
+            let _slot=slot;
+            let _=hand.read_client().await;
+            listener(shared, middleware_data, hand).await;
+        });
+    }
+}
+
+// releases a stream's place in the concurrency limit when its handler ends
+struct StreamSlot(Arc<AtomicUsize>);
+
+impl Drop for StreamSlot{
+    fn drop(&mut self){
+        self.0.fetch_sub(1, Ordering::SeqCst);
     }
-    Ok(())
 }
 
-async fn listener<'a,S:HttpSocket+Send+'static>(shared:Arc<SharedData>, middleware_data: Arc<MiddlewareData<S::Stream>>, hand: S)
+async fn listener<'a,S:HttpSocket+Send+'static>(shared:Arc<SharedData>, middleware_data: Arc<MiddlewareData<S::Stream>>, mut hand: S)
 // where S: HttpSocket
 {
+    advertise_h3(&shared, &middleware_data, &mut hand);
+    serve(&shared, &middleware_data, &mut hand).await;
+}
+
+/// serves requests on one http/1.1 connection until either side wants it closed
+async fn http1_listener<S:Stream+'static>(shared:Arc<SharedData>, middleware_data: Arc<MiddlewareData<S>>, mut hand: Http1Socket<S>){
+    let idle=Duration::from_secs(shared.http1.keep_alive_timeout);
+    let max_requests=shared.http1.max_requests.max(1);
+    let mut served=0;
+
+    loop{
+        if served>0{
+            hand.reset();
+            let mut shutdown=shared.shutdown.subscribe();
+            let next=tokio::select!{
+                r=tokio::time::timeout(idle, hand.read_client())=>r.map(|r|r.map(|_|())),
+                _=shutdown.wait_for(|s|*s)=>break,
+            };
+            match next{
+                Ok(Ok(()))=>(),
+                Ok(Err(e))=>{ println!("\x1b[31mkeep-alive connection ended\x1b[0m {e:?}"); break },
+                Err(_)=>{ println!("\x1b[31mkeep-alive timeout\x1b[0m"); break },
+            };
+        }
+        served+=1;
+
+        let (reusable,upgrade)=match hand.get_client().await{
+            Ok(c)=>(wants_keep_alive(c), c.headers.contains_key("upgrade")),
+            Err(_)=>(false,false),
+        };
+        let keep_alive=shared.http1.keep_alive && reusable && served<max_requests && !shared.shutdown.is_shutting_down();
+        if keep_alive{
+            let _=hand.set_header("Connection", "keep-alive");
+            let _=hand.set_header("Keep-Alive", &format!("timeout={}, max={}",idle.as_secs(),max_requests-served));
+        } else if !upgrade{
+            let _=hand.set_header("Connection", "close");
+        }
+
+        advertise_h3(&shared, &middleware_data, &mut hand);
+        serve(&shared, &middleware_data, &mut hand).await;
+        // upgraded connections belong to the websocket now
+        if !keep_alive || upgrade { break }
+    }
+    println!("\x1b[31mhttp/1.1 connection closed after {served} requests\x1b[0m");
+}
+
+// plain http and h2c clients couldnt use the quic endpoint, it needs tls
+fn advertise_h3<S:HttpSocket>(shared: &SharedData, middleware_data: &MiddlewareData<S::Stream>, hand: &mut S){
+    if !middleware_data.tls { return }
+    if let Some(alt_svc)=&shared.alt_svc{
+        let _=hand.set_header("Alt-Svc", alt_svc);
+    }
+}
+
+/// accepts quic connections until shutdown, every request stream is served on its own task
+async fn h3_listener(shared: Arc<SharedData>, middleware_data: Arc<MiddlewareData<DuplexStream>>, endpoint: quinn::Endpoint){
+    let mut shutdown=shared.shutdown.subscribe();
+    loop{
+        let incoming=tokio::select!{
+            i=endpoint.accept()=>match i{ Some(i)=>i, None=>break },
+            _=shutdown.wait_for(|s|*s)=>break,
+        };
+        let shared=Arc::clone(&shared);
+        let middleware_data=Arc::clone(&middleware_data);
+        tokio::spawn(async move {
+            let _guard=shared.shutdown.track();
+            let addr=incoming.remote_address();
+            let conn=match incoming.await{
+                Ok(c)=>c,
+                Err(e)=>{ eprintln!("quic handshake failed {e:?}"); return },
+            };
+            println!("\x1b[35mhttp/3 connection from {addr}\x1b[0m");
+            let middleware_data=Arc::new(middleware_data.with_session(http3::peer_cert(&conn), http3::server_name(&conn)));
+            let mut h3=match h3::server::Connection::new(h3_quinn::Connection::new(conn)).await{
+                Ok(h)=>h,
+                Err(e)=>{ eprintln!("h3 handshake failed {e:?}"); return },
+            };
+
+            let mut shutdown=shared.shutdown.subscribe();
+            let mut closing=false;
+            loop{
+                let resolver=tokio::select!{
+                    r=h3.accept()=>r,
+                    // GOAWAY, then keep accepting until the client is done with what it already sent
+                    _=async{ let _=shutdown.wait_for(|s|*s).await; }, if !closing=>{
+                        closing=true;
+                        if let Err(e)=h3.shutdown(0).await{ eprintln!("couldnt send h3 goaway {e:?}") }
+                        continue
+                    },
+                };
+                let resolver=match resolver{
+                    Ok(Some(r))=>r,
+                    Ok(None)=>break,
+                    Err(e)=>{ eprintln!("h3 connection error {e:?}"); break },
+                };
+                let shared=Arc::clone(&shared);
+                let middleware_data=Arc::clone(&middleware_data);
+                tokio::spawn(async move {
+                    let (req,stream)=match resolver.resolve_request().await{
+                        Ok(r)=>r,
+                        Err(e)=>{ eprintln!("couldnt read h3 request {e:?}"); return },
+                    };
+                    let mut hand=H3Socket::new(req, stream);
+                    let read=hand.read_client().await.map(|_|());
+                    match read{
+                        Ok(())=>serve(&shared, &middleware_data, &mut hand).await,
+                        Err(_) if hand.too_large()=>{
+                            println!("413 Content Too Large: h3 request body over {} bytes",http3::MAX_BODY_SIZE);
+                            if let Err(e)=hand.reject_too_large().await{ eprintln!("couldnt send 413 {e:?}") }
+                        },
+                        Err(e)=>eprintln!("couldnt read h3 body {e:?}"),
+                    }
+                });
+            }
+            println!("\x1b[31mhttp/3 connection closed\x1b[0m");
+        });
+    }
+}
+
+fn wants_keep_alive(client: &HttpClient)->bool{
+    let connection=client.headers.get("connection").map(|c|c.join(",").to_lowercase()).unwrap_or_default();
+    let tokens: Vec<&str>=connection.split(',').map(|t|t.trim()).collect();
+    // the handlers dont consume request bodies, leftovers would be parsed as the next request
+    let has_body=client.headers.get("content-length").is_some_and(|l|l[0].trim()!="0") || client.headers.contains_key("transfer-encoding");
+
+    if tokens.contains(&"close") || has_body { false }
+    else if client.version.eq_ignore_ascii_case("HTTP/1.0") { tokens.contains(&"keep-alive") }
+    else { true }
+}
+
+async fn serve<S:HttpSocket+Send+'static>(shared: &Arc<SharedData>, middleware_data: &Arc<MiddlewareData<S::Stream>>, hand: &mut S){
     // async move {
-    let shared=Arc::clone(&shared);
+    let _guard=shared.shutdown.track();
     
     let now=Instant::now();
-    let res = handlers::handler(shared, middleware_data, hand).await;
+    let res = handlers::handler(Arc::clone(shared), Arc::clone(middleware_data), hand).await;
     println!("\x1b[36mhandler took {}ms\x1b[0m",now.elapsed().as_nanos() as f64 /1000000.0);
     match res {
         Ok(())=>println!("\x1b[32mhandler didnt error\x1b[0m"),
         Err(err)=>eprintln!("\x1b[31mhandler errored\n{:?}\x1b[0m",err),
     };
     // }
-}
\ No newline at end of file
+}
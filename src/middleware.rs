@@ -3,22 +3,42 @@ use std::sync::Arc;
 use rust_http::{common::{HttpError, HttpResult, HttpSocket, Stream, /*Stream*/}, websocket::{WebSocket, WebSocketFrameType}};
 use tokio::sync::Mutex;
 
-use crate::structs::SharedData;
+use crate::{clientauth::ClientCert, structs::SharedData};
 // use std::{collections::HashMap};
 
 pub type SharedClients<S> = Arc<Mutex<Vec<Arc<WebSocket<S>>>>>;
 
 pub struct MiddlewareData<S:Stream>{
     pub clients: SharedClients<S>,
+    /// the verified client certificate of this connection, with `client_auth` enabled
+    pub peer: Option<Arc<ClientCert>>,
+    /// connections arrive over tls, only those get told about http/3
+    pub tls: bool,
+    /// the sni name the tls session was opened for, it picks the vhost
+    pub server_name: Option<String>,
 }
 impl<S:Stream> MiddlewareData<S>{
     pub fn empty()->Self{
         Self{
             clients: Arc::new(Mutex::new(Vec::new())),
+            peer: None,
+            tls: false,
+            server_name: None,
         }
     }
+
+    pub fn secure()->Self{
+        Self{ tls: true, ..Self::empty() }
+    }
+
+    /// the same websocket clients, seen from a tls session that presented `peer` and asked for `server_name`
+    pub fn with_session(&self, peer: Option<ClientCert>, server_name: Option<String>)->Self{
+        Self{ clients: Arc::clone(&self.clients), peer: peer.map(Arc::new), tls: self.tls, server_name }
+    }
 }
 
+pub const NAMES: [&str; 3]=["example", "ws-echo", "ws-broadcast"];
+
 pub fn available(path: &str)->Option<&'static str>{
     match path{
         s if s.starts_with("/internal/example")=>Some("example"),
@@ -28,7 +48,7 @@ pub fn available(path: &str)->Option<&'static str>{
     }
 }
 
-pub async fn call<S:HttpSocket+Sized+Send+'static>(name: &str, shared: &SharedData, middle_data: &MiddlewareData<S::Stream>, path: &str, mut res: S)->HttpResult<()>{
+pub async fn call<S:HttpSocket+Sized+Send+'static>(name: &str, shared: &SharedData, middle_data: &MiddlewareData<S::Stream>, path: &str, res: &mut S)->HttpResult<()>{
     match name{
         "example"=>example(shared, path, res).await,
         "ws-echo"=>ws_echo(shared, path, res).await,
@@ -42,11 +62,11 @@ pub async fn call<S:HttpSocket+Sized+Send+'static>(name: &str, shared: &SharedDa
 }
 
 
-async fn example<S:HttpSocket>(_shared: &SharedData, _path: &str, mut res: S)->HttpResult<()>{
+async fn example<S:HttpSocket>(_shared: &SharedData, _path: &str, res: &mut S)->HttpResult<()>{
     res.close(b"example endpoint\n").await
 }
 
-async fn ws_echo<S:HttpSocket+Sized+Send+'static>(_shared: &SharedData, _path: &str, mut res: S)->HttpResult<()>{
+async fn ws_echo<S:HttpSocket+Sized+Send+'static>(_shared: &SharedData, _path: &str, res: &mut S)->HttpResult<()>{
     let c=res.get_client().await?;
     match c.headers.get("upgrade").map(|h|h[0].as_str()).as_deref(){
         Some("websocket")=>{
@@ -88,7 +108,7 @@ async fn ws_echo<S:HttpSocket+Sized+Send+'static>(_shared: &SharedData, _path: &
 pub async fn ws_broadcast<S: HttpSocket + Sized + Send + 'static>(
     _shared: &SharedData,
     _path: &str,
-    mut res: S,
+    res: &mut S,
     clients: SharedClients<S::Stream>,
 ) -> HttpResult<()> {
     let c = res.get_client().await?;
@@ -0,0 +1,154 @@
+use std::time::SystemTime;
+
+use crate::conditional;
+
+// more ranges than this in a single request are treated as abuse and the header is ignored
+const MAX_RANGES: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RangeResult{
+    Full,
+    Partial(Vec<(u64,u64)>),
+    Unsatisfiable,
+}
+
+/// parses a `Range` header against a representation of `size` bytes.
+/// returned ranges are inclusive `(start, end)` pairs clamped to the file size.
+/// headers that arent valid byte ranges are ignored as RFC 9110 allows.
+pub fn parse_range(header: &str, size: u64)->RangeResult{
+    let header=header.trim();
+    let specs=match header.split_once('='){
+        Some((unit,specs)) if unit.trim().eq_ignore_ascii_case("bytes")=>specs,
+        _=>return RangeResult::Full,
+    };
+
+    let mut ranges=vec![];
+    let mut count=0;
+    for spec in specs.split(',').map(|s|s.trim()).filter(|s|!s.is_empty()){
+        count+=1;
+        if count>MAX_RANGES { return RangeResult::Full }
+
+        let (start,end)=match spec.split_once('-'){
+            Some(v)=>v,
+            None=>return RangeResult::Full,
+        };
+        let (start,end)=(start.trim(),end.trim());
+
+        if start.is_empty(){
+            // suffix range, last n bytes
+            let n: u64=match end.parse(){
+                Ok(n)=>n,
+                Err(_)=>return RangeResult::Full,
+            };
+            if n==0||size==0 { continue }
+            ranges.push((size.saturating_sub(n),size-1));
+        } else {
+            let start: u64=match start.parse(){
+                Ok(n)=>n,
+                Err(_)=>return RangeResult::Full,
+            };
+            let end: Option<u64>=if end.is_empty(){ None } else {
+                match end.parse(){
+                    Ok(n)=>Some(n),
+                    Err(_)=>return RangeResult::Full,
+                }
+            };
+            if end.is_some_and(|e|e<start) { return RangeResult::Full }
+            if start>=size { continue }
+            ranges.push((start,end.map_or(size-1,|e|e.min(size-1))));
+        }
+    }
+
+    if count==0 { RangeResult::Full }
+    else if ranges.is_empty() { RangeResult::Unsatisfiable }
+    else { RangeResult::Partial(ranges) }
+}
+
+/// evaluates `If-Range`. entity tags need a strong match and a date only matches
+/// when it is exactly the files modification time
+pub fn if_range_matches(header: &str, etag: &str, modified: Option<SystemTime>)->bool{
+    let header=header.trim();
+    if header.starts_with('"')||header.starts_with("W/"){
+        return conditional::strong_match(header, etag);
+    }
+    match (httpdate::parse_http_date(header),modified){
+        (Ok(date),Some(modified))=>httpdate::fmt_http_date(date)==httpdate::fmt_http_date(modified),
+        _=>false,
+    }
+}
+
+pub fn content_range(start: u64, end: u64, size: u64)->String{
+    format!("bytes {}-{}/{}",start,end,size)
+}
+
+pub fn boundary()->String{
+    let nanos=SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0,|d|d.as_nanos());
+    format!("static-serve-{:x}",nanos)
+}
+
+/// header block written before every part of a `multipart/byteranges` body
+pub fn part_header(boundary: &str, content_type: &str, start: u64, end: u64, size: u64)->String{
+    format!("\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",boundary,content_type,content_range(start,end,size))
+}
+
+pub fn closing_boundary(boundary: &str)->String{
+    format!("\r\n--{}--\r\n",boundary)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn single_ranges(){
+        assert_eq!(parse_range("bytes=0-99", 1000), RangeResult::Partial(vec![(0,99)]));
+        assert_eq!(parse_range("bytes=500-", 1000), RangeResult::Partial(vec![(500,999)]));
+        // an end past the file is clamped
+        assert_eq!(parse_range("bytes=900-5000", 1000), RangeResult::Partial(vec![(900,999)]));
+        assert_eq!(parse_range("Bytes = 10-19", 1000), RangeResult::Partial(vec![(10,19)]));
+    }
+
+    #[test]
+    fn suffix_ranges(){
+        assert_eq!(parse_range("bytes=-100", 1000), RangeResult::Partial(vec![(900,999)]));
+        // asking for more than there is gets the whole file
+        assert_eq!(parse_range("bytes=-5000", 1000), RangeResult::Partial(vec![(0,999)]));
+        assert_eq!(parse_range("bytes=-0", 1000), RangeResult::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-1", 0), RangeResult::Unsatisfiable);
+    }
+
+    #[test]
+    fn unsatisfiable(){
+        assert_eq!(parse_range("bytes=1000-", 1000), RangeResult::Unsatisfiable);
+        assert_eq!(parse_range("bytes=1000-1999,2000-", 1000), RangeResult::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeResult::Unsatisfiable);
+        // one satisfiable range is enough
+        assert_eq!(parse_range("bytes=5000-,0-0", 1000), RangeResult::Partial(vec![(0,0)]));
+    }
+
+    #[test]
+    fn multi_range(){
+        assert_eq!(parse_range("bytes=0-0,-1", 1000), RangeResult::Partial(vec![(0,0),(999,999)]));
+        assert_eq!(parse_range("bytes=0-9, 20-29 ,40-", 50), RangeResult::Partial(vec![(0,9),(20,29),(40,49)]));
+        let many=(0..=MAX_RANGES).map(|i|format!("{i}-{i}")).collect::<Vec<_>>().join(",");
+        assert_eq!(parse_range(&format!("bytes={many}"), 1000), RangeResult::Full);
+    }
+
+    #[test]
+    fn invalid_headers_are_ignored(){
+        for header in ["items=0-1", "bytes=", "bytes=abc", "bytes=5-1", "bytes=1-x", "bytes=--1", "0-1"]{
+            assert_eq!(parse_range(header, 1000), RangeResult::Full, "{header}");
+        }
+    }
+
+    #[test]
+    fn if_range(){
+        let modified=httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        assert!(if_range_matches("\"abc\"", "\"abc\"", Some(modified)));
+        assert!(!if_range_matches("W/\"abc\"", "W/\"abc\"", Some(modified)));
+        assert!(!if_range_matches("\"abc\"", "\"abd\"", Some(modified)));
+        assert!(if_range_matches("Wed, 21 Oct 2015 07:28:00 GMT", "\"abc\"", Some(modified)));
+        assert!(!if_range_matches("Wed, 21 Oct 2015 07:28:01 GMT", "\"abc\"", Some(modified)));
+        assert!(!if_range_matches("Wed, 21 Oct 2015 07:28:00 GMT", "\"abc\"", None));
+    }
+}
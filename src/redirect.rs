@@ -0,0 +1,142 @@
+use std::sync::Arc;
+
+use rust_http::{common::{HttpClient, HttpConstructor, HttpResult, HttpSocket}, http1::handler::Http1Socket};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{handlers::body, structs::SharedData, vhost};
+
+/// the `https://` url for the same host, path and query. `None` when the host is missing or malformed
+pub fn location(client: &HttpClient, https_port: u16)->Option<String>{
+    let host=client.headers.get("host").and_then(|h|h.first())?;
+    let host=vhost::normalize(host);
+    if host.is_empty() || !host.bytes().all(|b|b.is_ascii_alphanumeric()||b"-.:_".contains(&b)) { return None }
+    let host=if host.contains(':') { format!("[{host}]") } else { host };
+    let port=if https_port==443 { String::new() } else { format!(":{https_port}") };
+    Some(format!("https://{host}{port}{}",target_path(&client.path)))
+}
+
+// the path and query of an origin-form (`/a?b`) or absolute-form (`http://host/a?b`) target, `/` for anything else
+fn target_path(target: &str)->String{
+    if target.starts_with('/') { return target.to_owned() }
+    let Some((_,rest))=target.split_once("://") else { return "/".to_owned() };
+    match rest.find(['/','?']){
+        Some(i) if rest[i..].starts_with('/')=>rest[i..].to_owned(),
+        Some(i)=>format!("/{}",&rest[i..]),
+        None=>"/".to_owned(),
+    }
+}
+
+/// answers every request on a plain http listener with a redirect to https,
+/// except pending acme http-01 challenges which have to be served right here
+pub async fn listen(shared: Arc<SharedData>, server: TcpListener, status: u16, https_port: u16){
+    let mut shutdown=shared.shutdown.subscribe();
+    loop{
+        let (socket,addr)=tokio::select!{
+            r=server.accept()=>match r{
+                Ok(c)=>c,
+                Err(e)=>{ eprintln!("redirect listener accept failed {e:?}"); continue },
+            },
+            _=shutdown.wait_for(|s|*s)=>break,
+        };
+        let shared=Arc::clone(&shared);
+        tokio::spawn(async move {
+            let _guard=shared.shutdown.track();
+            let mut hand=Http1Socket::new(socket,addr);
+            if let Err(e)=answer(&shared, &mut hand, status, https_port).await{
+                eprintln!("redirect failed {e:?}");
+            }
+        });
+    }
+}
+
+async fn answer(shared: &SharedData, hand: &mut Http1Socket<TcpStream>, status: u16, https_port: u16)->HttpResult<()>{
+    let client=hand.read_client().await?.clone();
+    let head=client.method=="HEAD";
+    let _=hand.set_header("Connection", "close");
+    if let Some(key_authorization)=shared.acme.http01(&client.path){
+        println!("answering acme http-01 challenge");
+        hand.set_status(200, "OK".to_owned())?;
+        let _=hand.set_header("Content-Type", "application/octet-stream");
+        return hand.close(body(head, key_authorization.as_bytes())).await;
+    }
+
+    let _=hand.set_header("Content-Type", "text/plain");
+    match location(&client, https_port){
+        Some(url)=>{
+            println!("\x1b[36mredirecting to {url}\x1b[0m");
+            hand.set_status(status, reason(status).to_owned())?;
+            let _=hand.set_header("Location", &url);
+            hand.close(body(head, format!("moved to {url}\n").as_bytes())).await
+        },
+        None=>{
+            hand.set_status(400, "Bad Request".to_owned())?;
+            hand.close(body(head, b"missing or invalid host header\n")).await
+        },
+    }
+}
+
+pub fn reason(status: u16)->&'static str{
+    match status{
+        301=>"Moved Permanently",
+        302=>"Found",
+        303=>"See Other",
+        307=>"Temporary Redirect",
+        _=>"Permanent Redirect",
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn request(host: Option<&str>, target: &str)->HttpClient{
+        let mut client=HttpClient::empty();
+        client.method="GET".to_owned();
+        client.path=target.to_owned();
+        if let Some(host)=host { client.headers.insert("host".to_owned(), vec![host.to_owned()]); }
+        client
+    }
+
+    #[test]
+    fn locations(){
+        let cases=[
+            (Some("example.com"),"/",443,Some("https://example.com/")),
+            (Some("Example.COM:80"),"/a/b?x=1&y=2",443,Some("https://example.com/a/b?x=1&y=2")),
+            (Some("example.com."),"/a",443,Some("https://example.com/a")),
+            (Some("example.com"),"/a?q",8443,Some("https://example.com:8443/a?q")),
+            (Some("127.0.0.1:8080"),"/",8443,Some("https://127.0.0.1:8443/")),
+            (Some("[::1]:8080"),"/a",443,Some("https://[::1]/a")),
+            (Some("[2001:db8::1]"),"/?q=1",8443,Some("https://[2001:db8::1]:8443/?q=1")),
+            (Some("under_score.local"),"/",443,Some("https://under_score.local/")),
+            // the host header is what gets validated
+            (None,"/",443,None),
+            (Some(""),"/",443,None),
+            (Some("evil.com/path"),"/",443,None),
+            (Some("a b"),"/",443,None),
+            (Some("evil.com@good.com"),"/",443,None),
+            (Some("x\r\nSet-Cookie: a=b"),"/",443,None),
+        ];
+        for (host,target,port,expected) in cases{
+            assert_eq!(location(&request(host, target), port).as_deref(), expected, "{host:?} {target}");
+        }
+    }
+
+    #[test]
+    fn request_targets(){
+        let cases=[
+            ("/a/b?c=d","/a/b?c=d"),
+            ("http://example.com/a/b?c=d","/a/b?c=d"),
+            ("http://example.com:80/a","/a"),
+            ("http://example.com","/"),
+            ("http://example.com?q=1","/?q=1"),
+            ("HTTP://example.com/x","/x"),
+            ("*","/"),
+            ("example.com:443","/"),
+            ("","/"),
+        ];
+        for (target,expected) in cases{
+            assert_eq!(target_path(target), expected, "{target}");
+        }
+        assert_eq!(location(&request(Some("example.com"), "http://example.com/a?b=c"), 443).as_deref(), Some("https://example.com/a?b=c"));
+    }
+}
@@ -0,0 +1,113 @@
+use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Duration};
+
+use rust_http::{common::Stream, websocket::WebSocket};
+use tokio::sync::{watch, Notify};
+
+// close code 1001 "going away"
+const WS_GOING_AWAY: u16 = 1001;
+
+/// shared shutdown state. connections register themselves with `track` so the
+/// process can wait for them to finish before exiting
+pub struct Shutdown{
+    sender: watch::Sender<bool>,
+    active: AtomicUsize,
+    idle: Notify,
+}
+
+/// keeps a connection counted as in flight until dropped
+pub struct ConnectionGuard(Arc<Shutdown>);
+
+impl Shutdown{
+    pub fn new()->Arc<Self>{
+        let (sender,_)=watch::channel(false);
+        Arc::new(Self{ sender, active: AtomicUsize::new(0), idle: Notify::new() })
+    }
+
+    pub fn subscribe(&self)->watch::Receiver<bool>{
+        self.sender.subscribe()
+    }
+
+    pub fn is_shutting_down(&self)->bool{
+        *self.sender.borrow()
+    }
+
+    pub fn trigger(&self){
+        self.sender.send_replace(true);
+    }
+
+    pub fn track(self: &Arc<Self>)->ConnectionGuard{
+        self.active.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(Arc::clone(self))
+    }
+
+    pub fn active(&self)->usize{
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// waits until every tracked connection finished or `timeout` passed.
+    /// returns wether everything drained in time
+    pub async fn drain(&self, timeout: Duration)->bool{
+        tokio::time::timeout(timeout, async{
+            loop{
+                let notified=self.idle.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                if self.active()==0 { return }
+                notified.await;
+            }
+        }).await.is_ok()
+    }
+}
+
+impl Drop for ConnectionGuard{
+    fn drop(&mut self){
+        if self.0.active.fetch_sub(1, Ordering::SeqCst)==1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl std::fmt::Debug for Shutdown{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        f.debug_struct("Shutdown")
+            .field("shutting_down",&self.is_shutting_down())
+            .field("active",&self.active())
+            .finish()
+    }
+}
+
+/// resolves once an exit signal arrives, SIGINT everywhere and SIGTERM on unix
+pub async fn signal(){
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term=match signal(SignalKind::terminate()){
+            Ok(s)=>s,
+            Err(e)=>{
+                eprintln!("couldnt listen for SIGTERM {e:?}");
+                let _=tokio::signal::ctrl_c().await;
+                return;
+            },
+        };
+        tokio::select!{
+            _=tokio::signal::ctrl_c()=>println!("\x1b[31mSIGINT received\x1b[0m"),
+            _=term.recv()=>println!("\x1b[31mSIGTERM received\x1b[0m"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _=tokio::signal::ctrl_c().await;
+        println!("\x1b[31mctrl+c received\x1b[0m");
+    }
+}
+
+/// sends a going away close frame to every websocket client
+pub async fn close_websockets<S:Stream>(clients: &[Arc<WebSocket<S>>]){
+    let mut payload=WS_GOING_AWAY.to_be_bytes().to_vec();
+    payload.extend_from_slice(b"server shutting down");
+    for ws in clients{
+        if let Err(e)=ws.send_close(&payload).await{
+            eprintln!("couldnt close websocket {} {e:?}",ws.addr);
+        }
+    }
+}
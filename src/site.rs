@@ -0,0 +1,389 @@
+use std::{collections::HashMap, fmt, path::Path, sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime}};
+
+/// the files a deploy brings along, they are never served themselves
+pub const SITE_FILES: [&str; 2]=["_redirects","_headers"];
+
+// how often the files are looked at again for changes
+const RECHECK: Duration=Duration::from_secs(1);
+
+/// `_redirects` and `_headers` per serve directory, read again when they change
+#[derive(Clone, Default)]
+pub struct SiteFiles{
+    dirs: Arc<Mutex<HashMap<String,Cached>>>,
+}
+
+struct Cached{
+    rules: Arc<SiteRules>,
+    modified: [Option<SystemTime>; 2],
+    checked: Instant,
+}
+
+impl fmt::Debug for SiteFiles{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SiteFiles").field("dirs",&self.dirs.lock().unwrap().len()).finish()
+    }
+}
+
+impl SiteFiles{
+    /// the rules of `serve_dir`, empty when it has neither file. the lock is never held
+    /// while the files are looked at, a request only waits on the disk when they changed
+    pub async fn get(&self, serve_dir: &str)->Arc<SiteRules>{
+        let known={
+            let mut dirs=self.dirs.lock().unwrap();
+            match dirs.get_mut(serve_dir){
+                Some(cached) if cached.checked.elapsed()<RECHECK=>return Arc::clone(&cached.rules),
+                // marked checked right away so concurrent requests keep using the current rules
+                Some(cached)=>{ cached.checked=Instant::now(); Some((Arc::clone(&cached.rules),cached.modified)) },
+                None=>None,
+            }
+        };
+        let modified=modified(serve_dir).await;
+        if let Some((rules,_))=known.as_ref().filter(|(_,m)|*m==modified) { return Arc::clone(rules) }
+        let rules=Arc::new(SiteRules::load(serve_dir).await);
+        if known.is_some() { println!("\x1b[33mreloaded _redirects and _headers of {serve_dir}\x1b[0m") }
+        self.dirs.lock().unwrap().insert(serve_dir.to_owned(), Cached{ rules: Arc::clone(&rules), modified, checked: Instant::now() });
+        rules
+    }
+}
+
+async fn modified(serve_dir: &str)->[Option<SystemTime>; 2]{
+    let mut modified=[None; 2];
+    for (m,f) in modified.iter_mut().zip(SITE_FILES){
+        *m=tokio::fs::metadata(Path::new(serve_dir).join(f)).await.and_then(|m|m.modified()).ok();
+    }
+    modified
+}
+
+/// whether `path` names one of the site files
+pub fn is_site_file(path: &str)->bool{
+    SITE_FILES.iter().any(|f|path.strip_prefix('/')==Some(*f))
+}
+
+#[derive(Debug, Default)]
+pub struct SiteRules{
+    redirects: Vec<Redirect>,
+    headers: Vec<HeaderBlock>,
+}
+
+#[derive(Debug)]
+struct Redirect{
+    from: String,
+    /// `key=:placeholder` or `key=value`, all of them have to be in the query
+    query: Vec<(String,String)>,
+    to: String,
+    status: u16,
+    /// `!`, applies even when a file exists at the path
+    force: bool,
+}
+
+#[derive(Debug)]
+struct HeaderBlock{
+    path: String,
+    headers: Vec<(String,String)>,
+}
+
+/// what a matching `_redirects` rule wants
+#[derive(Debug)]
+pub struct Route{
+    pub status: u16,
+    /// the url to redirect to, or the path to serve instead for 200
+    pub to: String,
+    pub force: bool,
+}
+
+impl SiteRules{
+    async fn load(serve_dir: &str)->Self{
+        let read=|name: &str|tokio::fs::read_to_string(Path::new(serve_dir).join(name));
+        let (redirects,headers)=(read("_redirects").await.unwrap_or_default(),read("_headers").await.unwrap_or_default());
+        let rules=SiteRules{ redirects: parse_redirects(&redirects), headers: parse_headers(&headers) };
+        if !rules.redirects.is_empty() || !rules.headers.is_empty() {
+            println!("\x1b[34m{serve_dir}: {} redirects, {} header blocks\x1b[0m",rules.redirects.len(),rules.headers.len());
+        }
+        rules
+    }
+
+    /// the first rule matching `path` and `query`, with its placeholders filled in
+    pub fn route(&self, path: &str, query: &str)->Option<Route>{
+        let params: Vec<(&str,&str)>=query.split('&').filter(|p|!p.is_empty()).map(|p|p.split_once('=').unwrap_or((p,""))).collect();
+        self.redirects.iter().find_map(|rule|{
+            let mut bound=match_path(&rule.from, path)?;
+            for (key,value) in &rule.query{
+                let (_,given)=params.iter().find(|(k,_)|k==key)?;
+                match value.strip_prefix(':'){
+                    Some(name)=>bound.push((name.to_owned(),given.to_string())),
+                    None=>if value!=given { return None },
+                }
+            }
+            let mut to=substitute(&rule.to, &bound);
+            // the query goes along unless the rule is about the query or brings its own
+            if rule.query.is_empty() && !to.contains('?') && !query.is_empty() {
+                to=match to.split_once('#'){
+                    Some((url,fragment))=>format!("{url}?{query}#{fragment}"),
+                    None=>format!("{to}?{query}"),
+                };
+            }
+            Some(Route{ status: rule.status, to, force: rule.force })
+        })
+    }
+
+    /// headers of every block matching `path`, values of a repeated name are joined
+    pub fn headers(&self, path: &str)->Vec<(String,String)>{
+        let mut out: Vec<(String,String)>=vec![];
+        for block in self.headers.iter().filter(|b|match_path(&b.path, path).is_some()){
+            for (name,value) in &block.headers{
+                match out.iter_mut().find(|(n,_)|n.eq_ignore_ascii_case(name)){
+                    Some((_,v))=>{ v.push_str(", "); v.push_str(value) },
+                    None=>out.push((name.clone(),value.clone())),
+                }
+            }
+        }
+        out
+    }
+}
+
+// `/from [key=value...] /to [status[!]]`, conditions like Country= or Role= arent supported
+fn parse_redirects(text: &str)->Vec<Redirect>{
+    let mut rules=vec![];
+    for (n,line) in text.lines().enumerate(){
+        let line=line.trim();
+        if line.is_empty() || line.starts_with('#') { continue }
+        let mut tokens=line.split_whitespace();
+        let Some(from)=tokens.next() else { continue };
+        let mut query=vec![];
+        let mut to=None;
+        for token in tokens.by_ref(){
+            // targets are paths or urls, which can have a query of their own
+            match token.split_once('='){
+                Some((k,v)) if !token.starts_with('/') && !token.contains("://")=>query.push((k.to_owned(),v.to_owned())),
+                _=>{ to=Some(token.to_owned()); break },
+            }
+        }
+        let status=tokens.next().unwrap_or("301");
+        let (status,force)=match status.strip_suffix('!'){
+            Some(s)=>(s,true),
+            None=>(status,false),
+        };
+        let rest: Vec<&str>=tokens.collect();
+        let skip=|why: String|eprintln!("\x1b[33m_redirects line {} skipped, {why}\x1b[0m",n+1);
+        let Some(to)=to else { skip(format!("{from} has no target")); continue };
+        let Ok(status)=status.parse::<u16>() else { skip(format!("{status} isnt a status")); continue };
+        if !rest.is_empty() { skip(format!("conditions {} arent supported",rest.join(" "))); continue }
+        if !matches!(status,200|301|302|303|307|308) { skip(format!("status {status} isnt supported")); continue }
+        if status==200 && !to.starts_with('/') { skip(format!("rewrites to {to} would need proxying")); continue }
+        if !from.starts_with('/') { skip(format!("{from} isnt a path")); continue }
+        rules.push(Redirect{ from: from.to_owned(), query, to, status, force });
+    }
+    rules
+}
+
+// a path line, then indented `Name: value` lines
+fn parse_headers(text: &str)->Vec<HeaderBlock>{
+    let mut blocks: Vec<HeaderBlock>=vec![];
+    for (n,line) in text.lines().enumerate(){
+        let trimmed=line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') { continue }
+        if !line.starts_with(char::is_whitespace) {
+            blocks.push(HeaderBlock{ path: trimmed.to_owned(), headers: vec![] });
+            continue;
+        }
+        let valid=|name: &str|!name.is_empty() && name.bytes().all(|b|b.is_ascii_alphanumeric()||b"!#$%&'*+-.^_`|~".contains(&b));
+        match (blocks.last_mut(),trimmed.split_once(':')){
+            (Some(block),Some((name,value))) if valid(name.trim())=>block.headers.push((name.trim().to_owned(),value.trim().to_owned())),
+            _=>eprintln!("\x1b[33m_headers line {} skipped, expected Name: value under a path\x1b[0m",n+1),
+        }
+    }
+    blocks
+}
+
+/// matches `/news/:year/*` against a path, binding placeholders and `splat`. trailing slashes dont matter
+fn match_path(pattern: &str, path: &str)->Option<Vec<(String,String)>>{
+    let pattern: Vec<&str>=pattern.trim_end_matches('/').split('/').collect();
+    let segments: Vec<&str>=path.trim_end_matches('/').split('/').collect();
+    let mut bound=vec![];
+    for (i,part) in pattern.iter().enumerate(){
+        if *part=="*" && i==pattern.len()-1 {
+            bound.push(("splat".to_owned(),segments.get(i..).unwrap_or_default().join("/")));
+            return Some(bound);
+        }
+        let segment=segments.get(i)?;
+        match part.strip_prefix(':'){
+            Some(name) if !segment.is_empty()=>bound.push((name.to_owned(),segment.to_string())),
+            Some(_)=>return None,
+            None if part==segment=>(),
+            None=>return None,
+        }
+    }
+    (segments.len()==pattern.len()).then_some(bound)
+}
+
+// longer names first so `:slug` isnt eaten by `:s`
+fn substitute(to: &str, bound: &[(String,String)])->String{
+    let mut bound: Vec<&(String,String)>=bound.iter().collect();
+    bound.sort_by_key(|(name,_)|std::cmp::Reverse(name.len()));
+    let mut to=to.to_owned();
+    for (name,value) in bound{
+        to=to.replace(&format!(":{name}"), value);
+    }
+    to
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    type Pairs<'a>=&'a [(&'a str,&'a str)];
+
+    fn bound(pairs: &[(&str,&str)])->Vec<(String,String)>{
+        pairs.iter().map(|(n,v)|(n.to_string(),v.to_string())).collect()
+    }
+
+    #[test]
+    fn paths(){
+        let cases: [(&str,&str,Option<Pairs>); 14]=[
+            ("/about","/about",Some(&[])),
+            ("/about","/about/",Some(&[])),
+            ("/about/","/about",Some(&[])),
+            ("/about","/contact",None),
+            ("/about","/about/team",None),
+            ("/news/:year","/news/2024",Some(&[("year","2024")])),
+            ("/news/:year/:slug","/news/2024/launch",Some(&[("year","2024"),("slug","launch")])),
+            ("/news/:year","/news/",None),
+            ("/news/:year","/news",None),
+            ("/blog/*","/blog/a/b",Some(&[("splat","a/b")])),
+            ("/blog/*","/blog",Some(&[("splat","")])),
+            ("/blog/*","/blogs/a",None),
+            ("/*","/anything/at/all",Some(&[("splat","anything/at/all")])),
+            ("/:lang/*","/en/docs/intro",Some(&[("lang","en"),("splat","docs/intro")])),
+        ];
+        for (pattern,path,expected) in cases{
+            assert_eq!(match_path(pattern, path), expected.map(bound), "{pattern} against {path}");
+        }
+    }
+
+    #[test]
+    fn substitutes(){
+        let cases=[
+            ("/new/:splat",&[("splat","a/b")][..],"/new/a/b"),
+            ("/:year/:slug",&[("year","2024"),("slug","launch")],"/2024/launch"),
+            ("/:s/:slug",&[("s","x"),("slug","y")],"/x/y"),
+            ("/find?q=:q",&[("q","cats")],"/find?q=cats"),
+            ("/static",&[("splat","unused")],"/static"),
+        ];
+        for (to,pairs,expected) in cases{
+            assert_eq!(substitute(to, &bound(pairs)), expected);
+        }
+    }
+
+    #[test]
+    fn redirect_lines(){
+        let text="
+            # comments and blank lines are skipped
+            /old /new
+            /temp /elsewhere 302
+            /app/* /index.html 200
+            /shadow /real 301!
+            /search q=:q /find/:q
+            /lang lang=en /en 302
+            /out https://example.com/?a=b 308
+            /missing
+            /bad /x abc
+            /geo /x 302 Country=de
+            /teapot /x 418
+            /proxy https://example.com 200
+            nopath /x
+        ";
+        let rules=parse_redirects(text);
+        let expected: [(&str,&str,u16,bool,Pairs); 7]=[
+            ("/old","/new",301,false,&[]),
+            ("/temp","/elsewhere",302,false,&[]),
+            ("/app/*","/index.html",200,false,&[]),
+            ("/shadow","/real",301,true,&[]),
+            ("/search","/find/:q",301,false,&[("q",":q")]),
+            ("/lang","/en",302,false,&[("lang","en")]),
+            ("/out","https://example.com/?a=b",308,false,&[]),
+        ];
+        assert_eq!(rules.len(), expected.len());
+        for (rule,(from,to,status,force,query)) in rules.iter().zip(expected){
+            assert_eq!((rule.from.as_str(),rule.to.as_str(),rule.status,rule.force), (from,to,status,force));
+            assert_eq!(rule.query, bound(query), "{from}");
+        }
+    }
+
+    #[test]
+    fn routes(){
+        let rules=SiteRules{ redirects: parse_redirects("
+            /blog/* /posts/:splat 301
+            /search q=:q /find/:q 302
+            /lang lang=en /en
+            /app/* /index.html 200
+            /shadow /real 200!
+            /keep /kept#top
+        "), headers: vec![] };
+        let cases=[
+            ("/blog/a/b","",Some(("/posts/a/b",301,false))),
+            ("/blog/a","page=2",Some(("/posts/a?page=2",301,false))),
+            ("/search","q=cats&x=1",Some(("/find/cats",302,false))),
+            ("/search","x=1",None),
+            ("/lang","lang=en",Some(("/en",301,false))),
+            ("/lang","lang=de",None),
+            ("/app/settings","",Some(("/index.html",200,false))),
+            ("/shadow","",Some(("/real",200,true))),
+            ("/keep","a=1",Some(("/kept?a=1#top",301,false))),
+            ("/other","",None),
+        ];
+        for (path,query,expected) in cases{
+            let route=rules.route(path, query);
+            assert_eq!(route.as_ref().map(|r|(r.to.as_str(),r.status,r.force)), expected, "{path}?{query}");
+        }
+    }
+
+    #[test]
+    fn header_blocks(){
+        let text="
+# comment
+/*
+  X-Frame-Options: DENY
+  X-Robots-Tag: noindex
+/assets/*
+  Cache-Control: public, max-age=31536000
+  Link: </a.css>; rel=preload
+  Link: </b.js>; rel=preload
+  not a header
+  Bad Name: x
+/empty
+";
+        let blocks=parse_headers(text);
+        let got: Vec<(&str,Vec<(&str,&str)>)>=blocks.iter().map(|b|(b.path.as_str(),b.headers.iter().map(|(n,v)|(n.as_str(),v.as_str())).collect())).collect();
+        assert_eq!(got, vec![
+            ("/*",vec![("X-Frame-Options","DENY"),("X-Robots-Tag","noindex")]),
+            ("/assets/*",vec![("Cache-Control","public, max-age=31536000"),("Link","</a.css>; rel=preload"),("Link","</b.js>; rel=preload")]),
+            ("/empty",vec![]),
+        ]);
+
+        let rules=SiteRules{ redirects: vec![], headers: blocks };
+        assert_eq!(rules.headers("/assets/app.js"), bound(&[
+            ("X-Frame-Options","DENY"),("X-Robots-Tag","noindex"),
+            ("Cache-Control","public, max-age=31536000"),("Link","</a.css>; rel=preload, </b.js>; rel=preload"),
+        ]));
+        assert_eq!(rules.headers("/index.html"), bound(&[("X-Frame-Options","DENY"),("X-Robots-Tag","noindex")]));
+        // a header line without a path above it goes nowhere
+        assert!(parse_headers("  X-A: b").is_empty());
+    }
+
+    #[tokio::test]
+    async fn files_are_read_once_and_picked_up_again(){
+        let dir=std::env::temp_dir().join(format!("static-serve-site-{}",std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let serve_dir=dir.to_string_lossy().to_string();
+        let files=SiteFiles::default();
+        assert!(files.get(&serve_dir).await.route("/old", "").is_none());
+
+        std::fs::write(dir.join("_redirects"), "/old /new").unwrap();
+        // still within the recheck interval
+        assert!(files.get(&serve_dir).await.route("/old", "").is_none());
+        files.dirs.lock().unwrap().get_mut(&serve_dir).unwrap().checked-=RECHECK;
+        let route=files.get(&serve_dir).await.route("/old", "");
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(route.map(|r|r.to), Some("/new".to_owned()));
+    }
+}
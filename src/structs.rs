@@ -2,13 +2,39 @@
 
 use std::fmt;
 
+use std::sync::Arc;
+
+use rust_http::common::HttpClient;
+
 use tokio_rustls::TlsAcceptor;
 
+use crate::{acme::Challenges, conditional::EtagCache, config::{ClientRule, Http1Config, Http2Config}, headers::HeaderRules, shutdown::Shutdown, site::SiteFiles, tls::SharedAcceptor, vhost::HostTable};
+
 #[derive(Clone)]
 pub struct SharedData{
     pub mime: std::collections::HashMap<&'static str,&'static str>,
     pub serve_dir: String,
-    pub tls_acceptor: Option<TlsAcceptor>,
+    /// serve directory per `Host`
+    pub vhosts: HostTable<String>,
+    pub tls_acceptor: Option<SharedAcceptor>,
+    /// paths only clients with a matching certificate may request
+    pub client_rules: Vec<ClientRule>,
+    /// pending http-01 tokens
+    pub acme: Challenges,
+    pub etag_cache: Option<EtagCache>,
+    pub autoindex: bool,
+    /// `_redirects` and `_headers` per serve directory, unless turned off
+    pub site_files: Option<SiteFiles>,
+    pub compress_min_size: u64,
+    pub compressible: std::collections::HashMap<&'static str,bool>,
+    /// security preset, `[headers]` and `[[header_rules]]`
+    pub headers: HeaderRules,
+    pub middleware: Vec<String>,
+    pub http2: Http2Config,
+    pub http1: Http1Config,
+    /// `Alt-Svc` value pointing clients at the http/3 endpoint, when there is one
+    pub alt_svc: Option<String>,
+    pub shutdown: Arc<Shutdown>,
 }
 
 impl fmt::Debug for SharedData{
@@ -16,7 +42,98 @@ impl fmt::Debug for SharedData{
         f.debug_struct("SharedData")
             .field("mime",&self.mime.len())
             .field("serve_dir",&self.serve_dir)
+            .field("vhosts",&self.vhosts)
             .field("tls_acceptor", if self.tls_acceptor.is_some(){&"Some(TlsAcceptor)"}else{&"None"})
+            .field("client_rules",&self.client_rules)
+            .field("acme",&self.acme)
+            .field("strong_etags",&self.etag_cache.is_some())
+            .field("autoindex",&self.autoindex)
+            .field("site_files",&self.site_files)
+            .field("compress_min_size",&self.compress_min_size)
+            .field("compressible",&self.compressible.values().filter(|c|**c).count())
+            .field("headers",&self.headers)
+            .field("middleware",&self.middleware)
+            .field("http2",&self.http2)
+            .field("http1",&self.http1)
+            .field("alt_svc",&self.alt_svc)
+            .field("shutdown",&self.shutdown)
             .finish()
     }
-}
\ No newline at end of file
+}
+impl SharedData{
+    /// the acceptor for the next handshake, certificates may have been reloaded since the last one.
+    /// without `http2` it doesnt offer h2 in alpn
+    pub fn acceptor(&self, http2: bool)->Option<TlsAcceptor>{
+        let acceptors=self.tls_acceptor.as_ref()?.read().unwrap();
+        Some(if http2 { acceptors.h2.clone() } else { acceptors.http1.clone() })
+    }
+
+    /// the directory of the vhost named by `Host` (or `:authority`), the default one otherwise
+    pub fn host_dir(&self, client: &HttpClient)->&str{
+        ["host",":authority"].iter()
+            .filter_map(|h|client.headers.get(*h).and_then(|v|v.first()))
+            .find_map(|host|self.vhosts.get(host))
+            .map_or(&self.serve_dir,|dir|dir)
+    }
+
+    /// the directory of the vhost the tls session was opened for, by `Host` without sni.
+    /// None when `Host` leads to another site than sni, the request was misdirected
+    pub fn serve_dir_for(&self, client: &HttpClient, server_name: Option<&str>)->Option<&str>{
+        let Some(server_name)=server_name else { return Some(self.host_dir(client)) };
+        let dir=self.vhosts.get(server_name).map_or(self.serve_dir.as_str(),|d|d.as_str());
+        let has_host=["host",":authority"].iter().any(|h|client.headers.contains_key(*h));
+        (!has_host || self.host_dir(client)==dir).then_some(dir)
+    }
+}
+
+#[cfg(test)]
+pub mod tests{
+    use super::*;
+    use crate::{config::Config, shutdown::Shutdown};
+
+    pub fn shared()->SharedData{
+        let config=Config::default();
+        let mut vhosts=HostTable::new();
+        vhosts.insert("a.test", "./a".to_owned());
+        vhosts.insert("*.b.test", "./b".to_owned());
+        SharedData{
+            mime: Default::default(), serve_dir: "./public".to_owned(), vhosts, tls_acceptor: None, client_rules: vec![],
+            acme: Default::default(), etag_cache: None, autoindex: false, site_files: None, compress_min_size: 0,
+            compressible: Default::default(), headers: Default::default(), middleware: vec![],
+            http2: config.http2, http1: config.http1, alt_svc: None, shutdown: Shutdown::new(),
+        }
+    }
+
+    fn client(host: Option<&str>)->HttpClient{
+        let mut client=HttpClient::empty();
+        if let Some(host)=host { client.headers.insert("host".to_owned(), vec![host.to_owned()]); }
+        client
+    }
+
+    #[test]
+    fn host_without_sni(){
+        let shared=shared();
+        assert_eq!(shared.serve_dir_for(&client(Some("a.test")), None), Some("./a"));
+        assert_eq!(shared.serve_dir_for(&client(Some("x.b.test:8443")), None), Some("./b"));
+        assert_eq!(shared.serve_dir_for(&client(Some("other.test")), None), Some("./public"));
+        assert_eq!(shared.serve_dir_for(&client(None), None), Some("./public"));
+    }
+
+    #[test]
+    fn sni_picks_the_site(){
+        let shared=shared();
+        assert_eq!(shared.serve_dir_for(&client(None), Some("a.test")), Some("./a"));
+        assert_eq!(shared.serve_dir_for(&client(Some("A.test:443")), Some("a.test")), Some("./a"));
+        assert_eq!(shared.serve_dir_for(&client(Some("localhost")), Some("localhost")), Some("./public"));
+        // two names of the same site dont disagree
+        assert_eq!(shared.serve_dir_for(&client(Some("y.b.test")), Some("x.b.test")), Some("./b"));
+    }
+
+    #[test]
+    fn sni_and_host_disagree(){
+        let shared=shared();
+        assert_eq!(shared.serve_dir_for(&client(Some("x.b.test")), Some("a.test")), None);
+        assert_eq!(shared.serve_dir_for(&client(Some("a.test")), Some("localhost")), None);
+        assert_eq!(shared.serve_dir_for(&client(Some("other.test")), Some("a.test")), None);
+    }
+}
@@ -0,0 +1,410 @@
+use std::{fs::File, io::{BufReader, Error, ErrorKind}, path::Path, sync::{Arc, RwLock}, time::{Duration, SystemTime}};
+
+use p12_keystore::KeyStore;
+use rustls::{sign, Certificate, PrivateKey, ServerConfig, SignatureScheme};
+use rustls_pemfile::{certs, Item};
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+
+use crate::{acme::{self, Challenges}, clientauth, config::{AcmeChallenge, Config}, devcert, http3, vhost::{Certs, HostTable}};
+
+/// the acceptors new handshakes use, swapped out when certificates are reloaded
+pub type SharedAcceptor=Arc<RwLock<Acceptors>>;
+
+/// the same certificates with and without h2 in alpn, for listeners that turn http2 off
+#[derive(Clone)]
+pub struct Acceptors{
+    pub h2: TlsAcceptor,
+    pub http1: TlsAcceptor,
+}
+
+impl Acceptors{
+    pub fn new(sc: ServerConfig)->Self{
+        let mut http1=sc.clone();
+        http1.alpn_protocols.retain(|p|p!=b"h2");
+        Acceptors{ h2: TlsAcceptor::from(Arc::new(sc)), http1: TlsAcceptor::from(Arc::new(http1)) }
+    }
+}
+
+pub fn load_certs(path: &str) -> std::io::Result<Vec<Certificate>> {
+    let f = File::open(path)?;
+    let mut reader = BufReader::new(f);
+    let certs = certs(&mut reader)?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// the first key in a pem file. PKCS#8 (RSA, ECDSA or Ed25519), PKCS#1 RSA and SEC1 EC keys are understood
+pub fn load_private_key(path: &str) -> std::io::Result<PrivateKey> {
+    let f = File::open(path)?;
+    let mut reader = BufReader::new(f);
+
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            // BEGIN PRIVATE KEY, BEGIN RSA PRIVATE KEY and BEGIN EC PRIVATE KEY
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => return Ok(PrivateKey(ed25519_v1(key))),
+            _ => (),
+        }
+    }
+
+    Err(Error::new(ErrorKind::Unsupported,format!("no private keys found in {}", path)))
+}
+
+// rustls 0.20 reads Ed25519 PKCS#8 with ring 0.16, which wants the public key of a v2 document
+// explicitly tagged. openssl 3 and rcgen tag it implicitly like RFC 8410 says, the seed alone is enough
+fn ed25519_v1(key: Vec<u8>)->Vec<u8>{
+    const V2: [u8; 16]=[0x30,0x51,0x02,0x01,0x01,0x30,0x05,0x06,0x03,0x2b,0x65,0x70,0x04,0x22,0x04,0x20];
+    const V1: [u8; 16]=[0x30,0x2e,0x02,0x01,0x00,0x30,0x05,0x06,0x03,0x2b,0x65,0x70,0x04,0x22,0x04,0x20];
+    if key.len()==83 && key.starts_with(&V2) && key[48..51]==[0x81,0x21,0x00] {
+        return [&V1[..],&key[16..48]].concat();
+    }
+    key
+}
+
+/// `.p12` and `.pfx` files hold the key and its chain in one bundle
+pub fn is_pkcs12(path: &str)->bool{
+    Path::new(path).extension().and_then(|e|e.to_str()).is_some_and(|e|e.eq_ignore_ascii_case("p12")||e.eq_ignore_ascii_case("pfx"))
+}
+
+/// the first key and its chain from a PKCS#12 bundle, like the identity.p12 from gen-local-cert.sh
+pub fn load_pkcs12(path: &str, password: &str) -> std::io::Result<(PrivateKey,Vec<Certificate>)> {
+    let data=std::fs::read(path)?;
+    let store=KeyStore::from_pkcs12(&data, password).map_err(|e|Error::new(ErrorKind::InvalidData, format!("{path}: {e}")))?;
+    let (_,chain)=store.private_key_chain().ok_or_else(||Error::new(ErrorKind::InvalidData, format!("no private key in {path}")))?;
+    Ok((PrivateKey(ed25519_v1(chain.key().to_vec())), chain.chain().iter().map(|c|Certificate(c.as_der().to_vec())).collect()))
+}
+
+/// `password` is only used when `key_path` is a PKCS#12 bundle, `cert_path` is ignored then
+pub fn load_key_cert(key_path:&str,cert_path:&str,password:&str)->Option<(PrivateKey,Vec<Certificate>)>{
+    let (key,certs)=if is_pkcs12(key_path){
+        match load_pkcs12(key_path, password){
+            Ok(pair)=>pair,
+            Err(e)=>{
+                eprintln!("reading pkcs12 bundle failed {e:?}");
+                return None;
+            }
+        }
+    }else{
+        let key=match load_private_key(key_path){
+            Ok(k)=>k,
+            Err(e)=>{
+                eprintln!("reading private key failed {e:?}");
+                return None;
+            }
+        };
+        let certs=match load_certs(cert_path){
+            Ok(cs)=>cs,
+            Err(e)=>{
+                eprintln!("reading certificates failed {e:?}");
+                return None;
+            }
+        };
+        (key,certs)
+    };
+    if let Err(e)=key_matches_cert(&key, &certs){
+        eprintln!("private key {key_path} doesnt belong to the certificate: {e}");
+        return None;
+    }
+    println!("successfully read private key and certificates");
+    Some((key,certs))
+}
+
+/// signs a test message with `key` and checks it against the public key of the leaf certificate
+pub fn key_matches_cert(key: &PrivateKey, certs: &[Certificate])->Result<(),String>{
+    let leaf=certs.first().ok_or("no certificates")?;
+    let key=sign::any_supported_type(key).map_err(|_|"unsupported private key type")?;
+    let signer=key.choose_scheme(&[
+        SignatureScheme::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SignatureScheme::RSA_PSS_SHA256,
+    ]).ok_or("no usable signature scheme")?;
+    let alg=match signer.scheme(){
+        SignatureScheme::ED25519=>&webpki::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256=>&webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384=>&webpki::ECDSA_P384_SHA384,
+        _=>&webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    };
+
+    let message=b"static-serve key check";
+    let signature=signer.sign(message).map_err(|e|format!("signing failed {e:?}"))?;
+    let cert=webpki::EndEntityCert::try_from(leaf.0.as_slice()).map_err(|e|format!("unparsable certificate {e:?}"))?;
+    cert.verify_signature(alg, message, &signature).map_err(|_|"public key differs".to_owned())
+}
+
+/// the default pair, from acme or generated with `tls.dev`, plus one for every vhost that brings its own. pairs that fail to load are left out
+pub fn load_cert_table(config: &Config)->Certs{
+    let password=&config.tls.pkcs12_password.0;
+    let default=if config.acme.enabled { acme::current_pair(config) }
+        else if config.tls.dev { devcert::dev_pair(config) } else { load_key_cert(&config.tls.key_path, &config.tls.cert_path, password) };
+    let mut certs=Certs{ default, hosts: HostTable::new() };
+    for vhost in &config.vhosts{
+        let Some(key_path)=&vhost.key_path else { continue };
+        let cert_path=vhost.cert_path.as_deref().unwrap_or_default();
+        let password=vhost.pkcs12_password.as_ref().map_or(password,|p|&p.0);
+        if let Some(pair)=load_key_cert(key_path, cert_path, password){
+            for host in &vhost.hosts{ certs.hosts.insert(host, pair.clone()) }
+        }
+    }
+    certs
+}
+
+/// `challenges` are the tls-alpn-01 certificates handed out while acme validates a domain
+pub fn server_config(certs: &Certs, config: &Config, challenges: &Challenges)->Result<ServerConfig,String>{
+    let http2=&config.http2;
+    let resolver=certs.resolver().map_err(|e|format!("unusable private key {e:?}"))?;
+    let builder=ServerConfig::builder().with_safe_defaults();
+    let mut sc=clientauth::with_client_auth(builder, &config.client_auth)?
+        .with_cert_resolver(Arc::new(acme::Resolver{ inner: resolver, challenges: challenges.clone() }));
+    sc.alpn_protocols=vec![b"http/1.1".to_vec()];
+    if http2.enabled && http2.first { sc.alpn_protocols=vec![b"h2".to_vec(),b"http/1.1".to_vec()] }
+    else if http2.enabled { sc.alpn_protocols.push(b"h2".to_vec()) };
+    if config.acme.enabled && config.acme.challenge==AcmeChallenge::TlsAlpn01 { sc.alpn_protocols.push(acme::ACME_TLS_ALPN.to_vec()) }
+    Ok(sc)
+}
+
+// every key and certificate file the config points at
+fn cert_files(config: &Config)->Vec<String>{
+    let mut files=vec![config.tls.key_path.clone(),config.tls.cert_path.clone(),config.client_auth.ca_path.clone()];
+    for vhost in &config.vhosts{
+        files.extend(vhost.key_path.iter().chain(&vhost.cert_path).cloned());
+    }
+    files
+}
+
+fn modified(files: &[String])->Vec<Option<SystemTime>>{
+    files.iter().map(|f|std::fs::metadata(f).and_then(|m|m.modified()).ok()).collect()
+}
+
+/// loads everything again and swaps it in. on any failure the old certificates stay in use
+pub fn reload(config: &Config, acceptor: &SharedAcceptor, h3: Option<&quinn::Endpoint>, challenges: &Challenges)->Result<(),String>{
+    let certs=load_cert_table(config);
+    if certs.default.is_none() {
+        return Err("default certificate unusable".to_owned());
+    }
+    let loaded=certs.hosts.iter().count();
+    let expected: usize=config.vhosts.iter().filter(|v|v.key_path.is_some()).map(|v|v.hosts.len()).sum();
+    if loaded<expected {
+        return Err(format!("only {loaded} of {expected} vhost certificates usable"));
+    }
+
+    let sc=server_config(&certs, config, challenges)?;
+    let h3_config=match h3{
+        Some(_)=>Some(http3::server_config(&certs, &config.client_auth).map_err(|e|format!("quic config failed {e:?}"))?),
+        None=>None,
+    };
+    *acceptor.write().unwrap()=Acceptors::new(sc);
+    if let (Some(endpoint),Some(c))=(h3,h3_config){ endpoint.set_server_config(Some(c)) }
+    Ok(())
+}
+
+#[cfg(unix)]
+type Hangup=Option<tokio::signal::unix::Signal>;
+#[cfg(not(unix))]
+type Hangup=();
+
+#[cfg(unix)]
+fn hangup_signal()->Hangup{
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::hangup()){
+        Ok(s)=>Some(s),
+        Err(e)=>{ eprintln!("couldnt listen for SIGHUP {e:?}"); None },
+    }
+}
+#[cfg(not(unix))]
+fn hangup_signal()->Hangup{}
+
+async fn hangup(_sig: &mut Hangup){
+    #[cfg(unix)]
+    if let Some(s)=_sig{
+        s.recv().await;
+        return;
+    }
+    std::future::pending::<()>().await
+}
+
+/// reloads the certificates on SIGHUP and whenever one of the files changes,
+/// checking their mtimes every `tls.reload_interval` seconds
+pub async fn watch(config: Config, acceptor: SharedAcceptor, h3: Option<quinn::Endpoint>, challenges: Challenges, mut shutdown: watch::Receiver<bool>){
+    let files=cert_files(&config);
+    let mut last=modified(&files);
+    let mut sighup=hangup_signal();
+    let poll=config.tls.reload_interval>0;
+    let mut tick=tokio::time::interval(Duration::from_secs(config.tls.reload_interval.max(1)));
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop{
+        let reason=tokio::select!{
+            _=tick.tick(), if poll=>{
+                let now=modified(&files);
+                if now==last { continue }
+                last=now;
+                "certificate files changed"
+            },
+            _=hangup(&mut sighup)=>{
+                last=modified(&files);
+                "SIGHUP received"
+            },
+            _=shutdown.wait_for(|s|*s)=>return,
+        };
+        println!("\x1b[33m{reason}, reloading tls certificates\x1b[0m");
+        match reload(&config, &acceptor, h3.as_ref(), &challenges){
+            Ok(())=>println!("\x1b[32mtls certificates reloaded\x1b[0m"),
+            Err(e)=>eprintln!("\x1b[31mkeeping the old certificates\x1b[0m {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use p12_keystore::{KeyStoreEntry, PrivateKeyChain};
+    use rcgen::{CertificateParams, KeyPair, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256, PKCS_ECDSA_P384_SHA384, PKCS_ED25519};
+
+    fn pem(label: &str, der: &[u8])->String{
+        let body=STANDARD.encode(der);
+        let lines: Vec<&str>=body.as_bytes().chunks(64).map(|l|std::str::from_utf8(l).unwrap()).collect();
+        format!("-----BEGIN {label}-----\n{}\n-----END {label}-----\n",lines.join("\n"))
+    }
+
+    // content of the der value at the start of `der`, and what follows it
+    fn tlv(der: &[u8])->(&[u8],&[u8]){
+        let (len,start)=match der[1]{
+            l if l<0x80=>(l as usize,2),
+            l=>{
+                let n=(l&0x7f) as usize;
+                (der[2..2+n].iter().fold(0,|acc,b|acc<<8|*b as usize),2+n)
+            },
+        };
+        (&der[start..start+len],&der[start+len..])
+    }
+
+    // the ECPrivateKey inside a PKCS#8 document is the SEC1 encoding
+    fn sec1(pkcs8: &[u8])->Vec<u8>{
+        let (info,_)=tlv(pkcs8);
+        let (_,rest)=tlv(info);
+        let (_,rest)=tlv(rest);
+        let (key,_)=tlv(rest);
+        key.to_vec()
+    }
+
+    fn pair(alg: &'static SignatureAlgorithm)->(KeyPair,rcgen::Certificate){
+        let key=KeyPair::generate_for(alg).unwrap();
+        let cert=CertificateParams::new(vec!["localhost".to_owned()]).unwrap().self_signed(&key).unwrap();
+        (key,cert)
+    }
+
+    struct Dir(std::path::PathBuf);
+
+    impl Dir{
+        fn new(name: &str)->Self{
+            let dir=std::env::temp_dir().join(format!("static-serve-{name}-{}",std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Dir(dir)
+        }
+
+        fn write(&self, name: &str, data: impl AsRef<[u8]>)->String{
+            let path=self.0.join(name);
+            std::fs::write(&path, data).unwrap();
+            path.to_string_lossy().to_string()
+        }
+    }
+
+    impl Drop for Dir{
+        fn drop(&mut self){
+            let _=std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn key_formats(){
+        let dir=Dir::new("keys");
+        let cases=[
+            ("p256 pkcs8",&PKCS_ECDSA_P256_SHA256,false),
+            ("p384 pkcs8",&PKCS_ECDSA_P384_SHA384,false),
+            // written as v2 with the implicitly tagged public key
+            ("ed25519 pkcs8",&PKCS_ED25519,false),
+            ("p256 sec1",&PKCS_ECDSA_P256_SHA256,true),
+            ("p384 sec1",&PKCS_ECDSA_P384_SHA384,true),
+        ];
+        for (name,alg,as_sec1) in cases{
+            let (key,cert)=pair(alg);
+            let key_pem=if as_sec1 { pem("EC PRIVATE KEY", &sec1(&key.serialize_der())) } else { key.serialize_pem() };
+            let key_path=dir.write(&format!("{name}.key"), &key_pem);
+            let cert_path=dir.write(&format!("{name}.crt"), cert.pem());
+
+            let loaded=load_private_key(&key_path).unwrap();
+            assert!(sign::any_supported_type(&loaded).is_ok(), "{name}");
+            let (_,certs)=load_key_cert(&key_path, &cert_path, "").unwrap_or_else(||panic!("{name} didnt load"));
+            assert_eq!(certs[0].0, cert.der().to_vec(), "{name}");
+        }
+    }
+
+    #[test]
+    fn keys_in_other_pem_blocks(){
+        let dir=Dir::new("pem-blocks");
+        let (key,cert)=pair(&PKCS_ED25519);
+        // a certificate ahead of the key is skipped over
+        let path=dir.write("combined.pem", cert.pem()+&key.serialize_pem());
+        assert_eq!(load_private_key(&path).unwrap().0, ed25519_v1(key.serialize_der()));
+        let path=dir.write("cert-only.pem", cert.pem());
+        assert_eq!(load_private_key(&path).unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn mismatched_pairs_are_refused(){
+        let dir=Dir::new("mismatch");
+        let (key,_)=pair(&PKCS_ECDSA_P256_SHA256);
+        let (_,other)=pair(&PKCS_ECDSA_P256_SHA256);
+        let (ed_key,ed_cert)=pair(&PKCS_ED25519);
+        let ed_key=PrivateKey(ed25519_v1(ed_key.serialize_der()));
+
+        let key_der=PrivateKey(key.serialize_der());
+        assert_eq!(key_matches_cert(&key_der, &[Certificate(other.der().to_vec())]), Err("public key differs".to_owned()));
+        // a different key type cant sign for the certificate either
+        assert!(key_matches_cert(&ed_key, &[Certificate(other.der().to_vec())]).is_err());
+        assert!(key_matches_cert(&key_der, &[]).is_err());
+        assert!(key_matches_cert(&ed_key, &[Certificate(ed_cert.der().to_vec())]).is_ok());
+
+        let key_path=dir.write("a.key", key.serialize_pem());
+        let cert_path=dir.write("b.crt", other.pem());
+        assert!(load_key_cert(&key_path, &cert_path, "").is_none());
+    }
+
+    #[test]
+    fn ed25519_v2_keys(){
+        let (key,_)=pair(&PKCS_ED25519);
+        let v2=key.serialize_der();
+        assert_eq!(v2.len(), 83);
+        let v1=ed25519_v1(v2.clone());
+        assert_eq!(v1.len(), 48);
+        assert_eq!(v1[16..], v2[16..48]);
+        assert!(sign::any_eddsa_type(&PrivateKey(v1.clone())).is_ok());
+        // anything else is left alone
+        assert_eq!(ed25519_v1(v1.clone()), v1);
+        let (ec,_)=pair(&PKCS_ECDSA_P256_SHA256);
+        assert_eq!(ed25519_v1(ec.serialize_der()), ec.serialize_der());
+    }
+
+    #[test]
+    fn pkcs12_bundles(){
+        for (path,expected) in [("id.p12",true),("ID.PFX",true),("dir/id.pfx",true),("id.pem",false),("p12",false),("id.p12.bak",false),("id",false)]{
+            assert_eq!(is_pkcs12(path), expected, "{path}");
+        }
+
+        let dir=Dir::new("pkcs12");
+        let (key,cert)=pair(&PKCS_ECDSA_P256_SHA256);
+        let mut store=KeyStore::new();
+        let chain=PrivateKeyChain::new(key.serialize_der(), [1], [p12_keystore::Certificate::from_der(cert.der()).unwrap()]);
+        store.add_entry("identity", KeyStoreEntry::PrivateKeyChain(chain));
+        let path=dir.write("identity.p12", store.writer("secret").write().unwrap());
+
+        let (loaded,certs)=load_pkcs12(&path, "secret").unwrap();
+        assert_eq!(loaded.0, key.serialize_der());
+        assert_eq!(certs.len(), 1);
+        // the cert path is ignored for a bundle
+        assert!(load_key_cert(&path, "/does/not/exist", "secret").is_some());
+        assert_eq!(load_pkcs12(&path, "wrong").unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(load_key_cert(&path, "", "wrong").is_none());
+    }
+}
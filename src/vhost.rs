@@ -0,0 +1,113 @@
+use std::{fmt, sync::Arc};
+
+use rustls::{server::{ClientHello, ResolvesServerCert}, sign::{self, CertifiedKey}, Certificate, PrivateKey};
+
+/// lowercases and strips the port, brackets and trailing dot from a `Host` header or sni name
+pub fn normalize(host: &str)->String{
+    let host=host.trim();
+    let host=match host.strip_prefix('['){
+        // [::1]:8000
+        Some(v6)=>v6.split(']').next().unwrap_or(v6),
+        None=>match host.rsplit_once(':'){
+            Some((name,port)) if port.bytes().all(|b|b.is_ascii_digit())=>name,
+            _=>host,
+        },
+    };
+    host.trim_end_matches('.').to_lowercase()
+}
+
+// `*.example.com` covers exactly one more label, like certificate wildcards do
+fn wildcard_matches(pattern: &str, host: &str)->bool{
+    match (pattern.strip_prefix("*."),host.split_once('.')){
+        (Some(domain),Some((label,rest)))=>!label.is_empty() && rest==domain,
+        _=>false,
+    }
+}
+
+/// maps hostnames and `*.domain` wildcards to a value, exact names win over wildcards
+#[derive(Debug, Clone)]
+pub struct HostTable<T>{
+    entries: Vec<(String,T)>,
+}
+
+impl<T> HostTable<T>{
+    pub fn new()->Self{
+        Self{ entries: vec![] }
+    }
+
+    pub fn insert(&mut self, pattern: &str, value: T){
+        self.entries.push((normalize(pattern),value));
+    }
+
+    pub fn is_empty(&self)->bool{
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self)->impl Iterator<Item=(&str,&T)>{
+        self.entries.iter().map(|(p,v)|(p.as_str(),v))
+    }
+
+    pub fn get(&self, host: &str)->Option<&T>{
+        let host=normalize(host);
+        self.entries.iter().find(|(p,_)|*p==host)
+            .or_else(||self.entries.iter().find(|(p,_)|wildcard_matches(p,&host)))
+            .map(|(_,v)|v)
+    }
+}
+
+impl<T> Default for HostTable<T>{
+    fn default()->Self{
+        Self::new()
+    }
+}
+
+/// key and certificate chain for every host pattern, and the pair used when sni matches none of them
+#[derive(Clone, Default)]
+pub struct Certs{
+    pub default: Option<(PrivateKey,Vec<Certificate>)>,
+    pub hosts: HostTable<(PrivateKey,Vec<Certificate>)>,
+}
+
+impl Certs{
+    pub fn is_empty(&self)->bool{
+        self.default.is_none() && self.hosts.is_empty()
+    }
+
+    pub fn resolver(&self)->Result<SniResolver<CertifiedKey>,sign::SignError>{
+        let certified=|(key,certs): &(PrivateKey,Vec<Certificate>)|->Result<Arc<CertifiedKey>,sign::SignError>{
+            Ok(Arc::new(CertifiedKey::new(certs.clone(), sign::any_supported_type(key)?)))
+        };
+        let mut hosts=HostTable::new();
+        for (pattern,pair) in self.hosts.iter(){
+            hosts.insert(pattern, certified(pair)?);
+        }
+        Ok(SniResolver{ hosts, default: self.default.as_ref().map(certified).transpose()? })
+    }
+}
+
+/// picks the certificate by sni. generic so the quic listener with its own rustls version can share it
+pub struct SniResolver<K>{
+    pub hosts: HostTable<Arc<K>>,
+    pub default: Option<Arc<K>>,
+}
+
+impl<K> SniResolver<K>{
+    pub fn lookup(&self, server_name: Option<&str>)->Option<Arc<K>>{
+        server_name.and_then(|n|self.hosts.get(n)).or(self.default.as_ref()).cloned()
+    }
+}
+
+impl<K> fmt::Debug for SniResolver<K>{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        f.debug_struct("SniResolver")
+            .field("hosts",&self.hosts.iter().map(|(p,_)|p).collect::<Vec<_>>())
+            .field("default",&self.default.is_some())
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for SniResolver<CertifiedKey>{
+    fn resolve(&self, client_hello: ClientHello)->Option<Arc<CertifiedKey>>{
+        self.lookup(client_hello.server_name())
+    }
+}